@@ -0,0 +1,190 @@
+use crate::decimal::Decimal;
+use crate::pyth::Status;
+use crate::twap::{PriceUpdate, TwapAccumulator};
+use solana_program::pubkey::Pubkey;
+use std::collections::HashMap;
+
+// a component older than this many slots is left out of the aggregate
+pub const MAX_SEND_LATENCY: u64 = 25;
+
+pub struct AggregatePrice {
+    pub slot: u64,
+    pub price: Decimal,
+    pub conf: Decimal,
+    pub num_publishers: usize,
+}
+
+// reconstructed aggregate next to the one read from the price account
+pub struct AggregateComparison {
+    pub reconstructed: AggregatePrice,
+    pub onchain_price: Decimal,
+    pub onchain_conf: Decimal,
+    pub onchain_slot: u64,
+    // false when the on-chain aggregate slot is not in the window and the latest
+    // reconstructed aggregate is compared instead
+    pub matched_slot: bool,
+}
+
+impl AggregateComparison {
+    pub fn price_deviation(&self) -> Option<Decimal> {
+        self.reconstructed.price.checked_sub(&self.onchain_price)
+    }
+    pub fn conf_deviation(&self) -> Option<Decimal> {
+        self.reconstructed.conf.checked_sub(&self.onchain_conf)
+    }
+}
+
+// replay the publisher updates the way the pyth v2 program does
+// the first update landing in a new slot aggregates every component as it stood
+// before that slot, then the components take on the updates of the slot
+pub fn reconstruct(acc: &TwapAccumulator, min_publishers: usize) -> Vec<AggregatePrice> {
+    let expo = acc.expo();
+    let mut updates: Vec<&PriceUpdate> = acc.updates().collect();
+    updates.sort_by_key(|u| (u.slot, u.pub_slot));
+
+    let mut components: HashMap<Pubkey, &PriceUpdate> = HashMap::new();
+    let mut aggregates = Vec::new();
+    let mut current_slot: Option<u64> = None;
+    for u in updates {
+        if current_slot != Some(u.slot) {
+            current_slot = Some(u.slot);
+            let valid: Vec<(i64, u64)> = components
+                .values()
+                .filter(|c| c.pub_slot <= u.slot && u.slot - c.pub_slot <= MAX_SEND_LATENCY)
+                .filter(|c| c.status == Status::Trading && c.price > 0 && c.conf != 0)
+                // like the program, a component as uncertain as its price is left out
+                .filter(|c| (c.conf as i128) < c.price as i128)
+                .map(|c| (c.price, c.conf))
+                .collect();
+            if !valid.is_empty() && valid.len() >= min_publishers {
+                let (price, conf) = aggregate(&valid);
+                aggregates.push(AggregatePrice {
+                    slot: u.slot,
+                    price: Decimal::new(price, expo),
+                    conf: Decimal::new(conf, expo),
+                    num_publishers: valid.len(),
+                });
+            }
+        }
+        // a halted publisher still replaces its component, which then drops out of the aggregate
+        // the program rejects updates that are not newer than the component
+        let newer = match components.get(&u.publisher) {
+            Some(c) => u.pub_slot > c.pub_slot,
+            None => true,
+        };
+        if newer {
+            components.insert(u.publisher, u);
+        }
+    }
+    aggregates
+}
+
+// every publisher votes price - conf, price and price + conf
+// the aggregate is the median vote, its conf the distance to the further quartile
+pub fn aggregate(components: &[(i64, u64)]) -> (i128, i128) {
+    let mut votes: Vec<i128> = Vec::with_capacity(components.len() * 3);
+    for (price, conf) in components {
+        votes.push(*price as i128 - *conf as i128);
+        votes.push(*price as i128);
+        votes.push(*price as i128 + *conf as i128);
+    }
+    votes.sort_unstable();
+    let n = votes.len();
+    let median = if n % 2 == 1 {
+        votes[n / 2]
+    } else {
+        (votes[n / 2 - 1] + votes[n / 2]) / 2
+    };
+    let q25 = votes[n / 4];
+    let q75 = votes[n - 1 - n / 4];
+    (median, (median - q25).max(q75 - median))
+}
+
+// compare the reconstruction to the on-chain aggregate of the same slot if the window has it
+pub fn compare(
+    aggregates: Vec<AggregatePrice>,
+    onchain_price: Decimal,
+    onchain_conf: Decimal,
+    onchain_slot: u64,
+) -> Option<AggregateComparison> {
+    let matched_slot = aggregates.iter().any(|a| a.slot == onchain_slot);
+    let reconstructed = if matched_slot {
+        aggregates.into_iter().find(|a| a.slot == onchain_slot)?
+    } else {
+        aggregates.into_iter().last()?
+    };
+    Some(AggregateComparison {
+        reconstructed,
+        onchain_price,
+        onchain_conf,
+        onchain_slot,
+        matched_slot,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aggregate::{aggregate, reconstruct};
+    use crate::decimal::Decimal;
+    use crate::pyth::Status;
+    use crate::twap::{PriceUpdate, TwapAccumulator};
+    use solana_program::pubkey::Pubkey;
+
+    #[test]
+    fn votes_median_and_quartiles() {
+        // votes 90 95 99 [100] 100 100 101 105 110, quartiles 99 and 101
+        let (price, conf) = aggregate(&[(100, 10), (100, 5), (100, 1)]);
+        assert_eq!(price, 100);
+        assert_eq!(conf, 1);
+        let (price, conf) = aggregate(&[(100, 2)]);
+        assert_eq!((price, conf), (100, 2));
+    }
+    #[test]
+    fn aggregates_components_from_earlier_slots() {
+        let a = Pubkey::new(&[1; 32]);
+        let b = Pubkey::new(&[2; 32]);
+        let mut acc = TwapAccumulator::new(0);
+        for (slot, publisher, price) in [(10, a, 100), (11, b, 110), (12, a, 104), (50, b, 120)] {
+            acc.ingest(PriceUpdate {
+                pub_slot: slot,
+                slot: slot + 1,
+                block_time: 0,
+                price,
+                conf: 2,
+                status: Status::Trading,
+                publisher,
+            });
+        }
+        let aggregates = reconstruct(&acc, 1);
+        // slot 11 has nothing before it, slot 12 sees a, slot 13 sees a and b
+        // and by slot 51 both components are stale
+        let slots: Vec<u64> = aggregates.iter().map(|a| a.slot).collect();
+        assert_eq!(slots, vec![12, 13]);
+        assert_eq!(aggregates[0].price, Decimal::new(100, 0));
+        assert_eq!(aggregates[1].num_publishers, 2);
+        assert_eq!(aggregates[1].price, Decimal::new(105, 0));
+        assert_eq!(reconstruct(&acc, 2).len(), 1);
+    }
+    #[test]
+    fn leaves_out_conf_wider_than_price() {
+        let a = Pubkey::new(&[1; 32]);
+        let b = Pubkey::new(&[2; 32]);
+        let mut acc = TwapAccumulator::new(0);
+        for (slot, publisher, price, conf) in [(10, a, 100, 2), (10, b, 50, 50), (11, a, 100, 2)] {
+            acc.ingest(PriceUpdate {
+                pub_slot: slot,
+                slot,
+                block_time: 0,
+                price,
+                conf,
+                status: Status::Trading,
+                publisher,
+            });
+        }
+        // b's conf is not below its price, so slot 11 aggregates a alone
+        let aggregates = reconstruct(&acc, 1);
+        assert_eq!(aggregates.len(), 1);
+        assert_eq!(aggregates[0].num_publishers, 1);
+        assert_eq!(aggregates[0].price, Decimal::new(100, 0));
+    }
+}
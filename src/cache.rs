@@ -0,0 +1,278 @@
+use crate::pyth::Status;
+use crate::twap::PriceUpdate;
+use serde::{Deserialize, Serialize};
+use solana_program::pubkey::Pubkey;
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+// a price account signature as the rpc node listed it, failed transactions included
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CachedSignature {
+    pub signature: String,
+    pub slot: u64,
+    pub block_time: Option<i64>,
+    pub failed: bool,
+}
+
+// a decoded price update as it is written to disk
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UpdateRecord {
+    pub pub_slot: u64,
+    pub slot: u64,
+    pub block_time: i64,
+    pub status: String,
+    pub price: i64,
+    pub conf: u64,
+    pub publisher: String,
+}
+
+impl UpdateRecord {
+    pub fn new(u: &PriceUpdate) -> UpdateRecord {
+        UpdateRecord {
+            pub_slot: u.pub_slot,
+            slot: u.slot,
+            block_time: u.block_time,
+            status: u.status.name().to_string(),
+            price: u.price,
+            conf: u.conf,
+            publisher: u.publisher.to_string(),
+        }
+    }
+    pub fn update(&self) -> Result<PriceUpdate, &'static str> {
+        let status = match Status::parse(&self.status) {
+            Some(status) => status,
+            None => return Err("not a valid price status"),
+        };
+        let publisher = match Pubkey::from_str(&self.publisher) {
+            Ok(key) => key,
+            Err(_) => return Err("not a valid publisher key"),
+        };
+        Ok(PriceUpdate {
+            pub_slot: self.pub_slot,
+            slot: self.slot,
+            block_time: self.block_time,
+            price: self.price,
+            conf: self.conf,
+            status,
+            publisher,
+        })
+    }
+}
+
+// what has been fetched for one price account
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct AccountCache {
+    pub price_key: String,
+    // a run of the account signatures with none missing in between, newest first,
+    // so a later walk only pages the signatures before and after it
+    pub signatures: Vec<CachedSignature>,
+    // the decoded updates of every fetched transaction by signature, empty ones included
+    pub transactions: BTreeMap<String, Vec<UpdateRecord>>,
+}
+
+impl AccountCache {
+    pub fn new(price_key: &Pubkey) -> AccountCache {
+        AccountCache {
+            price_key: price_key.to_string(),
+            ..AccountCache::default()
+        }
+    }
+    pub fn newest(&self) -> Option<&CachedSignature> {
+        self.signatures.first()
+    }
+    pub fn oldest(&self) -> Option<&CachedSignature> {
+        self.signatures.last()
+    }
+    pub fn num_updates(&self) -> usize {
+        self.transactions.values().map(|t| t.len()).sum()
+    }
+    // drop the signatures and transactions older than the unix time before,
+    // the run is cut from its old end so it stays unbroken, returns the number dropped
+    pub fn prune(&mut self, before: i64) -> usize {
+        let cut = self
+            .signatures
+            .iter()
+            .position(|s| matches!(s.block_time, Some(t) if t < before))
+            .unwrap_or(self.signatures.len());
+        let dropped = self.signatures.split_off(cut);
+        for s in &dropped {
+            self.transactions.remove(&s.signature);
+        }
+        dropped.len()
+    }
+}
+
+// a directory holding one json file per price account
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Cache {
+        Cache { dir: dir.into() }
+    }
+    // $XDG_CACHE_HOME/pyth-twap, or ~/.cache/pyth-twap
+    pub fn default_dir() -> Option<PathBuf> {
+        let base = match env::var_os("XDG_CACHE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+        };
+        Some(base.join("pyth-twap"))
+    }
+    pub fn dir(&self) -> &PathBuf {
+        &self.dir
+    }
+    fn path(&self, price_key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", price_key))
+    }
+    // the cache of a price account, empty when nothing was cached or the file is unreadable
+    pub fn load(&self, price_key: &Pubkey) -> AccountCache {
+        let cached = fs::read(self.path(&price_key.to_string()))
+            .ok()
+            .and_then(|data| serde_json::from_slice::<AccountCache>(&data).ok());
+        match cached {
+            Some(cached) if cached.price_key == price_key.to_string() => cached,
+            _ => AccountCache::new(price_key),
+        }
+    }
+    pub fn store(&self, cached: &AccountCache) -> Result<(), &'static str> {
+        if fs::create_dir_all(&self.dir).is_err() {
+            return Err("cannot create the cache directory");
+        }
+        let data = match serde_json::to_vec(cached) {
+            Ok(data) => data,
+            Err(_) => return Err("cannot serialize the cache"),
+        };
+        // written aside and renamed so an interrupted run leaves the old file whole
+        let path = self.path(&cached.price_key);
+        let tmp = path.with_extension("json.tmp");
+        if fs::write(&tmp, data).is_err() || fs::rename(&tmp, &path).is_err() {
+            return Err("cannot write the cache file");
+        }
+        Ok(())
+    }
+    // every cached price account, unreadable files are left out
+    pub fn accounts(&self) -> Result<Vec<(AccountCache, u64)>, &'static str> {
+        let mut accounts = Vec::new();
+        for path in self.files()? {
+            let data = match fs::read(&path) {
+                Ok(data) => data,
+                Err(_) => continue,
+            };
+            if let Ok(cached) = serde_json::from_slice::<AccountCache>(&data) {
+                accounts.push((cached, data.len() as u64));
+            }
+        }
+        Ok(accounts)
+    }
+    // drop everything older than the unix time before, returns the number of signatures dropped
+    pub fn prune(&self, before: i64) -> Result<usize, &'static str> {
+        let mut dropped = 0;
+        for (mut cached, _) in self.accounts()? {
+            let n = cached.prune(before);
+            if n == 0 {
+                continue;
+            }
+            dropped += n;
+            if cached.signatures.is_empty() {
+                if fs::remove_file(self.path(&cached.price_key)).is_err() {
+                    return Err("cannot remove the cache file");
+                }
+            } else {
+                self.store(&cached)?;
+            }
+        }
+        Ok(dropped)
+    }
+    // remove every cache file, returns how many were removed
+    pub fn clear(&self) -> Result<usize, &'static str> {
+        let files = self.files()?;
+        for path in &files {
+            if fs::remove_file(path).is_err() {
+                return Err("cannot remove the cache file");
+            }
+        }
+        Ok(files.len())
+    }
+    fn files(&self) -> Result<Vec<PathBuf>, &'static str> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            // nothing was cached yet
+            Err(_) if !self.dir.exists() => return Ok(Vec::new()),
+            Err(_) => return Err("cannot read the cache directory"),
+        };
+        let mut files: Vec<PathBuf> = entries
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension() == Some(OsStr::new("json")))
+            .collect();
+        files.sort();
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::{AccountCache, Cache, CachedSignature, UpdateRecord};
+    use crate::pyth::Status;
+    use crate::twap::PriceUpdate;
+    use solana_program::pubkey::Pubkey;
+    use std::env;
+    use std::fs;
+
+    fn signature(n: u64) -> CachedSignature {
+        CachedSignature {
+            signature: format!("sig{}", n),
+            slot: n,
+            block_time: Some(n as i64 * 10),
+            failed: false,
+        }
+    }
+
+    #[test]
+    fn stores_and_prunes() {
+        let dir = env::temp_dir().join(format!("pyth-twap-cache-{}", std::process::id()));
+        let cache = Cache::new(&dir);
+        assert_eq!(cache.accounts().unwrap().len(), 0);
+
+        let key = Pubkey::new(&[7; 32]);
+        let update = PriceUpdate {
+            pub_slot: 2,
+            slot: 3,
+            block_time: 30,
+            price: 100,
+            conf: 1,
+            status: Status::Halted,
+            publisher: Pubkey::new(&[8; 32]),
+        };
+        let mut cached = AccountCache::new(&key);
+        cached.signatures = (1..=3).rev().map(signature).collect();
+        cached
+            .transactions
+            .insert("sig3".to_string(), vec![UpdateRecord::new(&update)]);
+        cached.transactions.insert("sig1".to_string(), Vec::new());
+        cache.store(&cached).unwrap();
+
+        let loaded = cache.load(&key);
+        assert_eq!(loaded, cached);
+        let back = loaded.transactions["sig3"][0].update().unwrap();
+        assert_eq!(back.status, Status::Halted);
+        assert_eq!(back.publisher, update.publisher);
+        assert_eq!(cache.load(&Pubkey::new(&[9; 32])).signatures.len(), 0);
+
+        // sig1 at time 10 and sig2 at time 20 are older than 25
+        assert_eq!(cache.prune(25), Ok(2));
+        let pruned = cache.load(&key);
+        assert_eq!(pruned.newest(), pruned.oldest());
+        assert_eq!(pruned.oldest().unwrap().signature, "sig3");
+        assert_eq!(pruned.num_updates(), 1);
+        assert!(!pruned.transactions.contains_key("sig1"));
+
+        assert_eq!(cache.clear(), Ok(1));
+        assert_eq!(cache.accounts().unwrap().len(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
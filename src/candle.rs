@@ -0,0 +1,213 @@
+use crate::decimal::Decimal;
+use crate::twap::{Ohlc, PriceUpdate, TwapAccumulator};
+use crate::window::{parse_duration, Window, WindowEnd};
+use chrono::{TimeZone, Utc};
+use serde_json::json;
+use std::collections::BTreeMap;
+
+// one bucket of the window, start and end are unix seconds or slots for slot windows
+pub struct Candle {
+    pub start: i64,
+    pub end: i64,
+    pub ohlc: Ohlc,
+    pub twap: Decimal,
+    pub twac: Decimal,
+    pub count: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Table,
+    Csv,
+    Json,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Result<Format, &'static str> {
+        match name {
+            "table" => Ok(Format::Table),
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            _ => Err("format should be one of table, csv or json"),
+        }
+    }
+}
+
+// candle size like 30s, 5m, 1h or 1d, slot windows take a plain number of slots
+pub fn parse_size(s: &str, window: &Window) -> Result<i64, &'static str> {
+    if let Window::Slots { .. } = window {
+        return match s.parse::<i64>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err("candle size of a slot window should be a positive number of slots"),
+        };
+    }
+    parse_duration(s)
+}
+
+// bucket the accepted updates into candles of size seconds (or slots) from the window start
+// each bucket is its own TWAP with the last update held until the bucket closes,
+// buckets without updates are left out
+pub fn candles(acc: &TwapAccumulator, window: &Window, size: i64) -> Vec<Candle> {
+    let (start, end) = match window {
+        Window::Time { start, end } => (start.timestamp(), end.timestamp()),
+        Window::Slots { from, to } => (*from as i64, (*to as i64).saturating_add(1)),
+    };
+    let position = |u: &PriceUpdate| match window {
+        Window::Time { .. } => u.block_time,
+        Window::Slots { .. } => u.pub_slot as i64,
+    };
+
+    let mut buckets: BTreeMap<i64, TwapAccumulator> = BTreeMap::new();
+    for u in acc.updates() {
+        let bucket = (position(u) - start).max(0) / size;
+        buckets
+            .entry(bucket)
+            .or_insert_with(|| TwapAccumulator::new(acc.expo()))
+            .ingest(u.clone());
+    }
+
+    let mut candles = Vec::with_capacity(buckets.len());
+    for (bucket, b) in buckets {
+        let candle_start = start + bucket * size;
+        let candle_end = (candle_start + size).min(end);
+        let until = match window {
+            Window::Time { .. } => WindowEnd::Time(candle_end),
+            Window::Slots { .. } => WindowEnd::Slot(candle_end as u64),
+        };
+        candles.push(Candle {
+            start: candle_start,
+            end: candle_end,
+            ohlc: b.ohlc().unwrap(),
+            twap: b.twap(until).unwrap(),
+            twac: b.twac(until).unwrap(),
+            count: b.count(),
+        });
+    }
+    candles
+}
+
+pub fn render(candles: &[Candle], window: &Window, format: Format) -> String {
+    let mut out = String::new();
+    match format {
+        Format::Table => {
+            out.push_str(&format!(
+                "{:<26} {:>20} {:>20} {:>20} {:>20} {:>20} {:>16} {:>8}\n",
+                "start", "open", "high", "low", "close", "twap", "conf", "updates"
+            ));
+            for c in candles {
+                let start = match window {
+                    Window::Time { .. } => Utc.timestamp_opt(c.start, 0).unwrap().to_rfc3339(),
+                    Window::Slots { .. } => c.start.to_string(),
+                };
+                out.push_str(&format!(
+                    "{:<26} {:>20} {:>20} {:>20} {:>20} {:>20} {:>16} {:>8}\n",
+                    start,
+                    c.ohlc.open,
+                    c.ohlc.high,
+                    c.ohlc.low,
+                    c.ohlc.close,
+                    c.twap,
+                    c.twac,
+                    c.count
+                ));
+            }
+        }
+        Format::Csv => {
+            out.push_str("start,end,open,high,low,close,twap,conf,updates\n");
+            for c in candles {
+                out.push_str(&format!(
+                    "{},{},{},{},{},{},{},{},{}\n",
+                    c.start,
+                    c.end,
+                    c.ohlc.open,
+                    c.ohlc.high,
+                    c.ohlc.low,
+                    c.ohlc.close,
+                    c.twap,
+                    c.twac,
+                    c.count
+                ));
+            }
+        }
+        Format::Json => {
+            // prices as strings so no digits are lost to json floats
+            let rows: Vec<_> = candles
+                .iter()
+                .map(|c| {
+                    json!({
+                        "start": c.start,
+                        "end": c.end,
+                        "open": c.ohlc.open.to_string(),
+                        "high": c.ohlc.high.to_string(),
+                        "low": c.ohlc.low.to_string(),
+                        "close": c.ohlc.close.to_string(),
+                        "twap": c.twap.to_string(),
+                        "conf": c.twac.to_string(),
+                        "updates": c.count,
+                    })
+                })
+                .collect();
+            out.push_str(&serde_json::to_string_pretty(&rows).unwrap());
+            out.push('\n');
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::candle::{candles, parse_size, render, Format};
+    use crate::pyth::Status;
+    use crate::twap::{PriceUpdate, TwapAccumulator};
+    use crate::window::Window;
+    use solana_program::pubkey::Pubkey;
+
+    fn update(pub_slot: u64, price: i64, conf: u64) -> PriceUpdate {
+        PriceUpdate {
+            pub_slot,
+            slot: pub_slot,
+            block_time: 0,
+            price,
+            conf,
+            status: Status::Trading,
+            publisher: Pubkey::default(),
+        }
+    }
+
+    #[test]
+    fn parses_sizes() {
+        let w = Window::slots(0, 10).unwrap();
+        assert_eq!(parse_size("25", &w), Ok(25));
+        assert!(parse_size("5m", &w).is_err());
+        let t = Window::last(chrono::Duration::minutes(60));
+        assert_eq!(parse_size("5m", &t), Ok(300));
+        assert_eq!(parse_size("1d", &t), Ok(86400));
+        assert!(parse_size("0m", &t).is_err());
+    }
+    #[test]
+    fn buckets_updates() {
+        let w = Window::slots(100, 129).unwrap();
+        let mut acc = TwapAccumulator::new(0);
+        for u in [
+            update(100, 10, 1),
+            update(105, 20, 3),
+            update(112, 30, 2),
+            update(125, 40, 1),
+        ] {
+            acc.ingest(u);
+        }
+        let candles = candles(&acc, &w, 10);
+        // slots 120-129 only has one update, 110-119 one, 100-109 two
+        assert_eq!(candles.len(), 3);
+        let first = &candles[0];
+        assert_eq!((first.start, first.end, first.count), (100, 110, 2));
+        // 10 for 5 slots, 20 for 5 slots
+        assert_eq!(first.twap.to_string(), "15");
+        assert_eq!(first.twac.to_string(), "2");
+        assert_eq!(candles[2].end, 130);
+
+        let csv = render(&candles, &w, Format::Csv);
+        assert_eq!(csv.lines().count(), 4);
+        assert!(csv.contains("100,110,10,20,10,20,15,2,2"));
+    }
+}
@@ -1,21 +1,97 @@
-use chrono::Duration;
-use clap::{App, Arg};
+use chrono::{Duration, Utc};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use pyth_twap::cache::Cache;
+use pyth_twap::candle::{self, Format};
+use pyth_twap::decimal::Decimal;
+use pyth_twap::filter::OutlierFilter;
+use pyth_twap::pyth::DEVNET_PROGRAM_ID;
+use pyth_twap::report::{DEFAULT_MAPPING_KEY, DEVNET_URL};
+use pyth_twap::retry::RetryPolicy;
+use pyth_twap::status::StatusPolicy;
+use pyth_twap::twap::Method;
+use pyth_twap::watch::websocket_url;
+use pyth_twap::window::{parse_duration, parse_time, Window};
+use std::path::PathBuf;
+
+// about 50 years, a window cannot start before the first solana block anyway
+const MAX_INTERVAL_MINUTES: i64 = 50 * 365 * 24 * 60;
+
+pub enum Command {
+    Twap(Box<Config>),
+    InspectTx(InspectConfig),
+    Cache(CacheConfig),
+    Watch(WatchConfig),
+}
+
+// follow the price account and print a rolling TWAP as new aggregates land
+pub struct WatchConfig {
+    pub symbol: String,
+    // the rolling window in seconds
+    pub interval: i64,
+    pub pyth_key: String,
+    // read the account every this many seconds instead of subscribing to it
+    pub poll: Option<u64>,
+    pub ws_url: String,
+    pub cache_dir: Option<PathBuf>,
+    pub debug: bool,
+    pub url: String,
+}
+
+// look after the on-disk cache of fetched transactions
+pub struct CacheConfig {
+    pub dir: PathBuf,
+    pub action: CacheAction,
+}
+
+pub enum CacheAction {
+    Inspect,
+    // drop what is older than this many seconds
+    Prune { older_than: i64 },
+    Clear,
+}
+
+// decode the pyth instructions of a single transaction
+pub struct InspectConfig {
+    pub signature: String,
+    pub program_id: String,
+    pub url: String,
+}
 
 pub struct Config {
     pub symbol: String,
-    pub interval: Duration,
+    pub window: Window,
+    pub method: Method,
+    pub max_deviation: Option<Decimal>,
+    pub candle_size: Option<i64>,
+    pub max_gap: Option<i64>,
+    pub min_coverage: Option<Decimal>,
+    pub format: Format,
+    pub output: Option<String>,
+    pub outliers: OutlierFilter,
+    pub status_policy: StatusPolicy,
+    pub statuses: bool,
+    pub publishers: bool,
+    pub reconstruct: bool,
+    pub min_publishers: usize,
     pub pyth_key: String,
+    pub concurrency: usize,
+    pub retry: RetryPolicy,
+    pub cache_dir: Option<PathBuf>,
+    pub export: Option<PathBuf>,
+    pub replay: Option<PathBuf>,
     pub debug: bool,
     pub url: String,
 }
 
-impl Config {
-    pub fn new() -> Result<Config, &'static str> {
+impl Command {
+    pub fn new() -> Result<Command, &'static str> {
         // validate command line arguements
         let matches = App::new("Pyth-TWAP")
             .version("0.1.0")
             .author("Conner <ConnerNGallagher@gmail.com>")
             .about("using pyth price oracle to calculate twap")
+            .setting(AppSettings::SubcommandsNegateReqs)
+            .setting(AppSettings::ArgsNegateSubcommands)
             .arg(
                 Arg::with_name("symbol")
                     .help("the symbol to calculate the TWAP for (BTC/USD)")
@@ -25,19 +101,22 @@ impl Config {
             .arg(
                 Arg::with_name("debug")
                     .help("print debug information verbosely")
-                    .short("d"),
+                    .short("d")
+                    .global(true),
             )
             .arg(
                 Arg::with_name("local")
                     .short("l")
-                    .help("run on a local instance of solana (http://localhost:8899)"),
+                    .help("run on a local instance of solana (http://localhost:8899)")
+                    .global(true),
             )
             .arg(
                 Arg::with_name("pyth")
                     .short("p")
                     .help("sets the public key of the pyth mapping account")
                     .takes_value(true)
-                    .default_value("BmA9Z6FjioHJPpjT39QazZyhDRUdZy2ezwx4GiDdE2u2")
+                    .default_value(DEFAULT_MAPPING_KEY)
+                    .global(true)
                     .required(false),
             )
             .arg(
@@ -48,8 +127,335 @@ impl Config {
                     .default_value("60")
                     .required(false),
             )
+            .arg(
+                Arg::with_name("start")
+                    .long("start")
+                    .help("the start of the TWAP window (RFC3339 or unix seconds), overrides the interval")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("end")
+                    .long("end")
+                    .help("the end of the TWAP window (RFC3339 or unix seconds), default is now")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("from-slot")
+                    .long("from-slot")
+                    .help("the first slot of a slot window, instead of a time window")
+                    .takes_value(true)
+                    .requires("to-slot")
+                    .conflicts_with_all(&["start", "end"])
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("to-slot")
+                    .long("to-slot")
+                    .help("the last slot of a slot window, instead of a time window")
+                    .takes_value(true)
+                    .requires("from-slot")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("method")
+                    .short("m")
+                    .long("method")
+                    .help("how prices are averaged")
+                    .takes_value(true)
+                    .possible_values(&["arithmetic", "geometric", "median", "ema", "conf"])
+                    .default_value("arithmetic")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("half-life")
+                    .long("half-life")
+                    .help("the ema half life in seconds, or slots for a slot window")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("max-deviation")
+                    .long("max-deviation")
+                    .help("exit with code 2 if the TWAP deviates from the on-chain TWAP by more than this percent")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("candle")
+                    .long("candle")
+                    .help("split the window into candles of this size (30s, 5m, 1h, 1d) or slots for a slot window")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("format")
+                    .long("format")
+                    .help("the candle output format")
+                    .takes_value(true)
+                    .possible_values(&["table", "csv", "json"])
+                    .default_value("table")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("output")
+                    .short("o")
+                    .long("output")
+                    .help("write the candles to a file instead of stdout")
+                    .takes_value(true)
+                    .requires("candle")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("max-gap")
+                    .long("max-gap")
+                    .help("report gaps between updates longer than this (30s, 5m, 1h) or slots for a slot window")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("min-coverage")
+                    .long("min-coverage")
+                    .help("do not calculate a TWAP when less than this percent of the window is covered by updates")
+                    .takes_value(true)
+                    .requires("max-gap")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("max-conf-ratio")
+                    .long("max-conf-ratio")
+                    .help("reject updates whose conf / price is above this ratio")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("conf-k")
+                    .long("conf-k")
+                    .help("reject updates more than k times their conf away from the rolling median")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("mad-k")
+                    .long("mad-k")
+                    .help("reject updates more than k median absolute deviations away from the rolling median")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("median-window")
+                    .long("median-window")
+                    .help("the number of updates the rolling median is taken over")
+                    .takes_value(true)
+                    .default_value("21")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("include-status")
+                    .long("include-status")
+                    .help("count updates with these statuses towards the TWAP besides trading (unknown,halted,auction)")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("statuses")
+                    .long("statuses")
+                    .help("print the time spent in each status and every status transition"),
+            )
+            .arg(
+                Arg::with_name("publishers")
+                    .long("publishers")
+                    .help("print a per publisher breakdown of the updates"),
+            )
+            .arg(
+                Arg::with_name("reconstruct")
+                    .long("reconstruct")
+                    .help("rebuild pyth's aggregate price from the publisher updates and compare it with the on-chain aggregate"),
+            )
+            .arg(
+                Arg::with_name("min-publishers")
+                    .long("min-publishers")
+                    .help("the number of valid publishers the reconstructed aggregate needs")
+                    .takes_value(true)
+                    .default_value("1")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("concurrency")
+                    .long("concurrency")
+                    .help("the number of transactions fetched at once")
+                    .takes_value(true)
+                    .default_value("8")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("max-attempts")
+                    .long("max-attempts")
+                    .help("the number of times a failing rpc call is made before giving up")
+                    .takes_value(true)
+                    .default_value("5")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("rps")
+                    .long("rps")
+                    .help("the most rpc calls made per second")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("cache-dir")
+                    .long("cache-dir")
+                    .help("the directory fetched transactions are cached in, default is ~/.cache/pyth-twap")
+                    .takes_value(true)
+                    .global(true)
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("no-cache")
+                    .long("no-cache")
+                    .help("fetch every transaction in the window without reading or writing the cache"),
+            )
+            .arg(
+                Arg::with_name("export")
+                    .long("export")
+                    .help("write the updates of the window and the price account to a file that --replay can read")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("replay")
+                    .long("replay")
+                    .help("calculate the TWAP from a file written by --export instead of an rpc node")
+                    .takes_value(true)
+                    .required(false),
+            )
+            .subcommand(
+                SubCommand::with_name("inspect-tx")
+                    .about("decode every pyth instruction of a transaction")
+                    .arg(
+                        Arg::with_name("signature")
+                            .help("the transaction signature")
+                            .index(1)
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("program")
+                            .long("program")
+                            .help("the public key of the pyth program")
+                            .takes_value(true)
+                            .default_value(DEVNET_PROGRAM_ID)
+                            .required(false),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("cache")
+                    .about("inspect, prune or clear the cache of fetched transactions")
+                    .setting(AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(
+                        SubCommand::with_name("inspect")
+                            .about("list the cached price accounts"),
+                    )
+                    .subcommand(
+                        SubCommand::with_name("prune")
+                            .about("drop cached transactions older than a duration")
+                            .arg(
+                                Arg::with_name("older-than")
+                                    .long("older-than")
+                                    .help("drop what is older than this (30m, 12h, 7d)")
+                                    .takes_value(true)
+                                    .required(true),
+                            ),
+                    )
+                    .subcommand(
+                        SubCommand::with_name("clear").about("remove every cached price account"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("watch")
+                    .about("follow a price account and print a rolling TWAP on every new aggregate")
+                    .arg(
+                        Arg::with_name("symbol")
+                            .help("the symbol to watch (BTC/USD)")
+                            .index(1)
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("interval")
+                            .short("i")
+                            .help("the rolling window in minutes")
+                            .takes_value(true)
+                            .default_value("60")
+                            .required(false),
+                    )
+                    .arg(
+                        Arg::with_name("poll")
+                            .long("poll")
+                            .help("read the price account every this many seconds instead of subscribing over websocket")
+                            .takes_value(true)
+                            .required(false),
+                    )
+                    .arg(
+                        Arg::with_name("ws")
+                            .long("ws")
+                            .help("the websocket url of the rpc node, default is derived from the rpc url")
+                            .takes_value(true)
+                            .conflicts_with("poll")
+                            .required(false),
+                    )
+                    .arg(
+                        Arg::with_name("no-cache")
+                            .long("no-cache")
+                            .help("fetch the history without reading or writing the cache"),
+                    ),
+            )
             .get_matches();
 
+        let url = rpc_url(&matches);
+        if let Some(inspect) = matches.subcommand_matches("inspect-tx") {
+            println!("{:.<20} {}", "Solana RPC Url", url);
+            return Ok(Command::InspectTx(InspectConfig {
+                signature: inspect.value_of("signature").unwrap().to_string(),
+                program_id: inspect.value_of("program").unwrap().to_string(),
+                url,
+            }));
+        }
+        if let Some(cache) = matches.subcommand_matches("cache") {
+            return CacheConfig::new(cache).map(Command::Cache);
+        }
+        if let Some(watch) = matches.subcommand_matches("watch") {
+            return WatchConfig::new(watch, url).map(Command::Watch);
+        }
+        Config::new(&matches, url).map(|c| Command::Twap(Box::new(c)))
+    }
+}
+
+impl CacheConfig {
+    fn new(matches: &ArgMatches) -> Result<CacheConfig, &'static str> {
+        let (action, sub) = match matches.subcommand() {
+            ("inspect", Some(sub)) => (CacheAction::Inspect, sub),
+            ("prune", Some(sub)) => {
+                let older_than = parse_duration(sub.value_of("older-than").unwrap())?;
+                (CacheAction::Prune { older_than }, sub)
+            }
+            ("clear", Some(sub)) => (CacheAction::Clear, sub),
+            _ => return Err("cache needs one of inspect, prune or clear"),
+        };
+        let dir = match sub.value_of("cache-dir") {
+            Some(dir) => PathBuf::from(dir),
+            None => match Cache::default_dir() {
+                Some(dir) => dir,
+                None => return Err("no home directory, set the cache directory with --cache-dir"),
+            },
+        };
+        println!("{:.<20} {}", "cache directory", dir.display());
+        Ok(CacheConfig { dir, action })
+    }
+}
+
+impl WatchConfig {
+    fn new(matches: &ArgMatches, url: String) -> Result<WatchConfig, &'static str> {
         let symbol = matches
             .value_of("symbol")
             .unwrap()
@@ -57,41 +463,231 @@ impl Config {
             .to_ascii_uppercase();
         println!("{:.<20} {}", "symbol", symbol);
 
-        let interval = matches
-            .value_of("interval")
-            .unwrap()
-            .parse::<i64>()
-            .unwrap();
-        if interval == 0 || interval > 1440 {
-            // panic
-            return Err("interval should be between 1 and 1440 minutes (1 day)");
-        }
-        let interval = Duration::seconds(interval.checked_mul(60).unwrap());
-        println!(
-            "{:.<20} {} minute(s)",
-            "TWAP interval",
-            interval.num_minutes()
-        );
+        let interval = parse_interval(matches)?.num_seconds();
+        println!("{:.<20} {} minutes", "rolling window", interval / 60);
 
         let pyth_key = matches.value_of("pyth").unwrap().to_string();
         if pyth_key.len() != 44 {
             return Err("pyth key is wrong number of characters");
         }
 
-        let mut url = "http://api.devnet.solana.com";
-        if matches.is_present("local") {
-            url = "http://localhost";
-        }
+        let poll = match matches.value_of("poll") {
+            Some(secs) => match secs.parse::<u64>() {
+                Ok(n) if n > 0 => Some(n),
+                _ => return Err("poll should be a positive number of seconds"),
+            },
+            None => None,
+        };
+        let ws_url = match matches.value_of("ws") {
+            Some(ws) => ws.to_string(),
+            None => websocket_url(&url)?,
+        };
+        let cache_dir = match matches.value_of("cache-dir") {
+            _ if matches.is_present("no-cache") => None,
+            Some(dir) => Some(PathBuf::from(dir)),
+            None => Cache::default_dir(),
+        };
         let debug = matches.is_present("debug");
 
         println!("{:.<20} {}", "Solana RPC Url", url);
+        match poll {
+            Some(secs) => println!("{:.<20} every {}s", "polling", secs),
+            None => println!("{:.<20} {}", "websocket Url", ws_url),
+        }
 
-        Ok(Config {
+        Ok(WatchConfig {
             symbol,
             interval,
             pyth_key,
+            poll,
+            ws_url,
+            cache_dir,
+            debug,
+            url,
+        })
+    }
+}
+
+// the -i interval in minutes, bounded so the window start cannot overflow
+fn parse_interval(matches: &ArgMatches) -> Result<Duration, &'static str> {
+    let minutes = match matches.value_of("interval").unwrap().parse::<i64>() {
+        Ok(i) if i > 0 => i,
+        _ => return Err("interval should be a positive number of minutes"),
+    };
+    if minutes > MAX_INTERVAL_MINUTES {
+        return Err("interval is too large");
+    }
+    Ok(Duration::minutes(minutes))
+}
+
+fn rpc_url(matches: &ArgMatches) -> String {
+    if matches.is_present("local") {
+        return "http://localhost".to_string();
+    }
+    DEVNET_URL.to_string()
+}
+
+impl Config {
+    fn new(matches: &ArgMatches, url: String) -> Result<Config, &'static str> {
+        let symbol = matches
+            .value_of("symbol")
+            .unwrap()
+            .to_string()
+            .to_ascii_uppercase();
+        println!("{:.<20} {}", "symbol", symbol);
+
+        let interval = parse_interval(matches)?;
+
+        let window = if let Some(from) = matches.value_of("from-slot") {
+            let from = match from.parse::<u64>() {
+                Ok(slot) => slot,
+                Err(_) => return Err("from slot should be a slot number"),
+            };
+            let to = match matches.value_of("to-slot").unwrap().parse::<u64>() {
+                Ok(slot) => slot,
+                Err(_) => return Err("to slot should be a slot number"),
+            };
+            Window::slots(from, to)?
+        } else {
+            // the window ends now unless given, and starts one interval before the end unless given
+            let end = match matches.value_of("end") {
+                Some(t) => parse_time(t)?,
+                None => Utc::now(),
+            };
+            let start = match matches.value_of("start") {
+                Some(t) => parse_time(t)?,
+                None => match end.checked_sub_signed(interval) {
+                    Some(start) => start,
+                    None => return Err("interval is too large"),
+                },
+            };
+            Window::between(start, end)?
+        };
+        println!("{:.<20} {}", "TWAP window", window);
+
+        let half_life = match matches.value_of("half-life") {
+            Some(h) => match h.parse::<i64>() {
+                Ok(h) => Some(h),
+                Err(_) => return Err("half life should be a number"),
+            },
+            None => None,
+        };
+        let method = Method::parse(matches.value_of("method").unwrap(), half_life)?;
+        println!("{:.<20} {}", "TWAP method", method.name());
+
+        let max_deviation = match matches.value_of("max-deviation") {
+            Some(m) => Some(Decimal::parse(m)?),
+            None => None,
+        };
+
+        let candle_size = match matches.value_of("candle") {
+            Some(size) => Some(candle::parse_size(size, &window)?),
+            None => None,
+        };
+        let max_gap = match matches.value_of("max-gap") {
+            Some(gap) => Some(candle::parse_size(gap, &window)?),
+            None => None,
+        };
+        let min_coverage = match matches.value_of("min-coverage") {
+            Some(pct) => Some(Decimal::parse(pct)?),
+            None => None,
+        };
+        let format = Format::parse(matches.value_of("format").unwrap())?;
+        let output = matches.value_of("output").map(|o| o.to_string());
+
+        let decimal_arg = |name: &str| match matches.value_of(name) {
+            Some(v) => Decimal::parse(v).map(Some),
+            None => Ok(None),
+        };
+        let median_window = match matches.value_of("median-window").unwrap().parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => return Err("median window should be a positive number of updates"),
+        };
+        let outliers = OutlierFilter {
+            max_conf_ratio: decimal_arg("max-conf-ratio")?,
+            conf_k: decimal_arg("conf-k")?,
+            mad_k: decimal_arg("mad-k")?,
+            median_window,
+        };
+
+        let status_policy = match matches.value_of("include-status") {
+            Some(list) => StatusPolicy::parse(list)?,
+            None => StatusPolicy::default(),
+        };
+        let statuses = matches.is_present("statuses");
+
+        let publishers = matches.is_present("publishers");
+
+        let reconstruct = matches.is_present("reconstruct");
+        let min_publishers = match matches.value_of("min-publishers").unwrap().parse::<usize>() {
+            Ok(n) => n,
+            Err(_) => return Err("min publishers should be a number"),
+        };
+
+        let pyth_key = matches.value_of("pyth").unwrap().to_string();
+        if pyth_key.len() != 44 {
+            return Err("pyth key is wrong number of characters");
+        }
+
+        let concurrency = match matches.value_of("concurrency").unwrap().parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => return Err("concurrency should be a positive number"),
+        };
+        let max_attempts = match matches.value_of("max-attempts").unwrap().parse::<u32>() {
+            Ok(n) if n > 0 => n,
+            _ => return Err("max attempts should be a positive number"),
+        };
+        let requests_per_second = match matches.value_of("rps") {
+            Some(rps) => match rps.parse::<u32>() {
+                Ok(n) if n > 0 => Some(n),
+                _ => return Err("rps should be a positive number"),
+            },
+            None => None,
+        };
+        let retry = RetryPolicy {
+            max_attempts,
+            requests_per_second,
+            ..RetryPolicy::default()
+        };
+        // no home directory to put the default cache in runs without one
+        let cache_dir = match matches.value_of("cache-dir") {
+            _ if matches.is_present("no-cache") => None,
+            Some(dir) => Some(PathBuf::from(dir)),
+            None => Cache::default_dir(),
+        };
+        let export = matches.value_of("export").map(PathBuf::from);
+        let replay = matches.value_of("replay").map(PathBuf::from);
+        let debug = matches.is_present("debug");
+
+        match &replay {
+            Some(path) => println!("{:.<20} {}", "replay file", path.display()),
+            None => println!("{:.<20} {}", "Solana RPC Url", url),
+        }
+
+        Ok(Config {
+            symbol,
+            window,
+            method,
+            max_deviation,
+            candle_size,
+            max_gap,
+            min_coverage,
+            format,
+            output,
+            outliers,
+            status_policy,
+            statuses,
+            publishers,
+            reconstruct,
+            min_publishers,
+            pyth_key,
+            concurrency,
+            retry,
+            cache_dir,
+            export,
+            replay,
             debug,
-            url: url.to_string(),
+            url,
         })
     }
 }
@@ -0,0 +1,113 @@
+use crate::decimal::Decimal;
+use crate::twap::{PriceUpdate, TwapAccumulator};
+use crate::window::Window;
+
+// a stretch of the window without accepted updates longer than the threshold
+pub struct Gap {
+    // publish slot and block time of the updates either side, None at the window edges
+    pub before: Option<(u64, i64)>,
+    pub after: Option<(u64, i64)>,
+    // seconds, or slots for a slot window
+    pub length: i64,
+}
+
+pub struct Coverage {
+    pub gaps: Vec<Gap>,
+    // the window length and how much of it is not in a gap
+    pub total: i64,
+    pub covered: i64,
+}
+
+impl Coverage {
+    // percent of the window covered by updates
+    pub fn pct(&self) -> Decimal {
+        if self.total == 0 {
+            return Decimal::new(100, 0);
+        }
+        Decimal::percent(self.covered, self.total)
+    }
+}
+
+// find every gap longer than threshold between consecutive updates and the window edges,
+// a gap counts against coverage for its full length
+pub fn detect(acc: &TwapAccumulator, window: &Window, threshold: i64) -> Coverage {
+    let (start, end) = match window {
+        Window::Time { start, end } => (start.timestamp(), end.timestamp()),
+        Window::Slots { from, to } => (*from as i64, (*to as i64).saturating_add(1)),
+    };
+    let position = |u: &PriceUpdate| match window {
+        Window::Time { .. } => u.block_time,
+        Window::Slots { .. } => u.pub_slot as i64,
+    };
+
+    let mut gaps = Vec::new();
+    let mut previous: Option<&PriceUpdate> = None;
+    for u in acc.updates() {
+        let from = previous.map(position).unwrap_or(start);
+        let length = position(u) - from;
+        if length > threshold {
+            gaps.push(Gap {
+                before: previous.map(|p| (p.pub_slot, p.block_time)),
+                after: Some((u.pub_slot, u.block_time)),
+                length,
+            });
+        }
+        previous = Some(u);
+    }
+    let length = end - previous.map(position).unwrap_or(start);
+    if length > threshold {
+        gaps.push(Gap {
+            before: previous.map(|p| (p.pub_slot, p.block_time)),
+            after: None,
+            length,
+        });
+    }
+
+    let total = (end - start).max(0);
+    let uncovered: i64 = gaps.iter().map(|g| g.length).sum();
+    Coverage {
+        gaps,
+        total,
+        covered: (total - uncovered).max(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coverage::detect;
+    use crate::decimal::Decimal;
+    use crate::pyth::Status;
+    use crate::twap::{PriceUpdate, TwapAccumulator};
+    use crate::window::Window;
+    use solana_program::pubkey::Pubkey;
+
+    #[test]
+    fn finds_gaps() {
+        let w = Window::slots(100, 199).unwrap();
+        let mut acc = TwapAccumulator::new(0);
+        for slot in [105, 110, 150, 160] {
+            acc.ingest(PriceUpdate {
+                pub_slot: slot,
+                slot,
+                block_time: 0,
+                price: 100,
+                conf: 1,
+                status: Status::Trading,
+                publisher: Pubkey::default(),
+            });
+        }
+        let coverage = detect(&acc, &w, 20);
+        // 110-150 inside and 160-200 at the end of the window
+        assert_eq!(coverage.gaps.len(), 2);
+        assert_eq!(coverage.gaps[0].before.unwrap().0, 110);
+        assert_eq!(coverage.gaps[0].after.unwrap().0, 150);
+        assert!(coverage.gaps[1].after.is_none());
+        assert_eq!((coverage.total, coverage.covered), (100, 20));
+        assert_eq!(coverage.pct(), Decimal::new(20, 0));
+
+        let empty = detect(&TwapAccumulator::new(0), &w, 20);
+        assert_eq!(empty.gaps.len(), 1);
+        assert!(empty.gaps[0].before.is_none());
+        assert_eq!(empty.covered, 0);
+    }
+}
@@ -0,0 +1,271 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::Neg;
+
+// fixed point decimal, value = mantissa * 10^expo
+// pyth prices are an i64 price with an i32 exponent, the i128 mantissa
+// leaves room to sum prices weighted by durations without overflowing
+// arithmetic that could still overflow is checked and returns None
+#[derive(Clone, Copy, Debug)]
+pub struct Decimal {
+    pub mantissa: i128,
+    pub expo: i32,
+}
+
+impl Decimal {
+    pub fn new(mantissa: i128, expo: i32) -> Decimal {
+        Decimal { mantissa, expo }
+    }
+    pub fn from_price(price: i64, expo: i32) -> Decimal {
+        Decimal::new(price as i128, expo)
+    }
+    pub fn zero(expo: i32) -> Decimal {
+        Decimal::new(0, expo)
+    }
+    // change the exponent, rounding half away from zero when digits are dropped
+    pub fn rescale(&self, expo: i32) -> Option<Decimal> {
+        if expo <= self.expo {
+            let factor = pow10(self.expo - expo)?;
+            return Some(Decimal::new(self.mantissa.checked_mul(factor)?, expo));
+        }
+        match pow10(expo - self.expo) {
+            Some(factor) => Some(Decimal::new(div_round(self.mantissa, factor), expo)),
+            // more digits dropped than an i128 has
+            None => Some(Decimal::zero(expo)),
+        }
+    }
+    pub fn mul_int(&self, factor: i128) -> Option<Decimal> {
+        Some(Decimal::new(self.mantissa.checked_mul(factor)?, self.expo))
+    }
+    // divide by an integer, rounding the result half away from zero at the given exponent
+    pub fn div_int(&self, divisor: i128, expo: i32) -> Option<Decimal> {
+        if divisor == 0 {
+            return None;
+        }
+        if expo <= self.expo {
+            let numerator = self.mantissa.checked_mul(pow10(self.expo - expo)?)?;
+            return Some(Decimal::new(div_round(numerator, divisor), expo));
+        }
+        let divisor = divisor.checked_mul(pow10(expo - self.expo)?)?;
+        Some(Decimal::new(div_round(self.mantissa, divisor), expo))
+    }
+    // part of whole in percent at two decimals, an empty whole counts as one
+    pub fn percent(part: i64, whole: i64) -> Decimal {
+        // an i64 times 10^4 always fits the mantissa
+        Decimal::new(div_round(part as i128 * 10_000, whole.max(1) as i128), -2)
+    }
+    pub fn checked_add(&self, other: &Decimal) -> Option<Decimal> {
+        let (a, b, expo) = align(self, other)?;
+        Some(Decimal::new(a.checked_add(b)?, expo))
+    }
+    pub fn checked_sub(&self, other: &Decimal) -> Option<Decimal> {
+        let (a, b, expo) = align(self, other)?;
+        Some(Decimal::new(a.checked_sub(b)?, expo))
+    }
+    pub fn checked_mul(&self, other: &Decimal) -> Option<Decimal> {
+        let mantissa = self.mantissa.checked_mul(other.mantissa)?;
+        Some(Decimal::new(mantissa, self.expo.checked_add(other.expo)?))
+    }
+    pub fn abs(&self) -> Decimal {
+        Decimal::new(self.mantissa.abs(), self.expo)
+    }
+    pub fn is_zero(&self) -> bool {
+        self.mantissa == 0
+    }
+    // divide by another decimal, rounding half away from zero at the given exponent
+    pub fn div(&self, divisor: &Decimal, expo: i32) -> Option<Decimal> {
+        if divisor.is_zero() {
+            return None;
+        }
+        let shift = self.expo - divisor.expo - expo;
+        let mantissa = if shift >= 0 {
+            div_round(self.mantissa.checked_mul(pow10(shift)?)?, divisor.mantissa)
+        } else {
+            div_round(self.mantissa, divisor.mantissa.checked_mul(pow10(-shift)?)?)
+        };
+        Some(Decimal::new(mantissa, expo))
+    }
+    // parse a plain decimal string like 0.25 or -12, keeping every digit given
+    // at most MAX_DIGITS significant and fractional digits, so a value times a price
+    // still fits the mantissa
+    pub fn parse(s: &str) -> Result<Decimal, &'static str> {
+        let (int, frac) = match s.find('.') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => (s, ""),
+        };
+        if frac.len() > MAX_DIGITS {
+            return Err("decimal has too many fractional digits");
+        }
+        let digits = format!("{}{}", int, frac);
+        let mantissa = match digits.parse::<i128>() {
+            Ok(m) => m,
+            Err(_) => return Err("not a decimal number"),
+        };
+        if frac.starts_with('-') || frac.starts_with('+') {
+            return Err("not a decimal number");
+        }
+        if mantissa.unsigned_abs() >= 10u128.pow(MAX_DIGITS as u32) {
+            return Err("decimal has too many digits");
+        }
+        Ok(Decimal::new(mantissa, -(frac.len() as i32)))
+    }
+    // lossy conversion, only for display or statistics that are not exact anyway
+    pub fn to_f64(&self) -> f64 {
+        self.mantissa as f64 * 10f64.powi(self.expo)
+    }
+}
+
+// the most digits Decimal::parse takes, an i64 price has 19
+pub const MAX_DIGITS: usize = 18;
+
+fn pow10(exp: i32) -> Option<i128> {
+    if exp < 0 {
+        return None;
+    }
+    10i128.checked_pow(exp as u32)
+}
+
+fn div_round(numerator: i128, divisor: i128) -> i128 {
+    let quotient = numerator / divisor;
+    let remainder = numerator % divisor;
+    if remainder.abs() * 2 < divisor.abs() {
+        return quotient;
+    }
+    if (numerator < 0) != (divisor < 0) {
+        quotient - 1
+    } else {
+        quotient + 1
+    }
+}
+
+// bring two values onto the smaller (more precise) exponent, None if one does not fit
+fn align(a: &Decimal, b: &Decimal) -> Option<(i128, i128, i32)> {
+    let expo = a.expo.min(b.expo);
+    Some((a.rescale(expo)?.mantissa, b.rescale(expo)?.mantissa, expo))
+}
+impl Neg for Decimal {
+    type Output = Decimal;
+    fn neg(self) -> Decimal {
+        Decimal::new(-self.mantissa, self.expo)
+    }
+}
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Decimal) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for Decimal {}
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Decimal) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Decimal {
+    fn cmp(&self, other: &Decimal) -> Ordering {
+        match align(self, other) {
+            Some((a, b, _)) => a.cmp(&b),
+            // the value with the larger exponent does not fit at the smaller one,
+            // so it is further from zero than the other and its sign decides
+            None if self.expo > other.expo => self.mantissa.cmp(&0),
+            None => 0.cmp(&other.mantissa),
+        }
+    }
+}
+
+// exact decimal string, keeps every digit of the exponent (12.3400 for 123400 * 10^-4)
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.mantissa < 0 { "-" } else { "" };
+        let digits = self.mantissa.unsigned_abs().to_string();
+        if self.expo >= 0 {
+            let s = format!("{}{}{}", sign, digits, "0".repeat(self.expo as usize));
+            return f.pad(&s);
+        }
+        let scale = (-self.expo) as usize;
+        let digits = format!("{:0>width$}", digits, width = scale + 1);
+        let (int, frac) = digits.split_at(digits.len() - scale);
+        f.pad(&format!("{}{}.{}", sign, int, frac))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decimal::Decimal;
+
+    #[test]
+    fn display_exact() {
+        assert_eq!(
+            Decimal::new(4312345678901, -8).to_string(),
+            "43123.45678901"
+        );
+        assert_eq!(Decimal::new(-5, -3).to_string(), "-0.005");
+        assert_eq!(Decimal::new(12, 2).to_string(), "1200");
+        assert_eq!(Decimal::new(0, -2).to_string(), "0.00");
+    }
+    #[test]
+    fn rescale_rounds_half_away_from_zero() {
+        assert_eq!(Decimal::new(125, -2).rescale(-1).unwrap().mantissa, 13);
+        assert_eq!(Decimal::new(-125, -2).rescale(-1).unwrap().mantissa, -13);
+        assert_eq!(Decimal::new(124, -2).rescale(-1).unwrap().mantissa, 12);
+        assert_eq!(Decimal::new(12, -1).rescale(-3).unwrap().mantissa, 1200);
+    }
+    #[test]
+    fn div_int_at_exponent() {
+        let d = Decimal::new(10, 0).div_int(3, -4).unwrap();
+        assert_eq!(d.to_string(), "3.3333");
+        let d = Decimal::new(2000, -2).div_int(3, 0).unwrap();
+        assert_eq!(d.to_string(), "7");
+    }
+    #[test]
+    fn compares_across_exponents() {
+        assert_eq!(Decimal::new(1, 0), Decimal::new(100, -2));
+        assert!(Decimal::new(101, -2) > Decimal::new(1, 0));
+        assert_eq!(
+            Decimal::new(1, 0)
+                .checked_sub(&Decimal::new(25, -2))
+                .unwrap()
+                .to_string(),
+            "0.75"
+        );
+    }
+    #[test]
+    fn multiplies_decimals() {
+        let d = Decimal::new(15, -1).checked_mul(&Decimal::new(25, -2));
+        assert_eq!(d.unwrap().to_string(), "0.375");
+    }
+    #[test]
+    fn overflows_without_panicking() {
+        let big = Decimal::new(i128::MAX / 10, 0);
+        assert!(big.rescale(-2).is_none());
+        assert!(big.checked_mul(&big).is_none());
+        assert!(big.checked_add(&Decimal::new(1, -2)).is_none());
+        assert_eq!(Decimal::new(5, 0).rescale(60).unwrap(), Decimal::zero(0));
+        assert!(Decimal::new(1, 0).div_int(3, -60).is_none());
+        // the comparison still holds when the two cannot be aligned
+        assert!(big > Decimal::new(1, -30));
+        assert!(-big < Decimal::new(-1, -30));
+    }
+    #[test]
+    fn divides_decimals() {
+        let a = Decimal::new(150, -2);
+        let b = Decimal::new(3, 0);
+        assert_eq!(a.div(&b, -3).unwrap().to_string(), "0.500");
+        assert_eq!(b.div(&a, 0).unwrap().to_string(), "2");
+        assert!(a.div(&Decimal::zero(-2), 0).is_none());
+    }
+    #[test]
+    fn parses_decimal_strings() {
+        assert_eq!(Decimal::parse("0.25").unwrap().to_string(), "0.25");
+        assert_eq!(Decimal::parse("-12").unwrap(), Decimal::new(-12, 0));
+        assert_eq!(Decimal::parse("-.5").unwrap(), Decimal::new(-5, -1));
+        assert!(Decimal::parse("1.-5").is_err());
+        assert_eq!(
+            Decimal::parse("0.000000000000000001").unwrap(),
+            Decimal::new(1, -18)
+        );
+        assert!(Decimal::parse("0.0000000000000000001").is_err());
+        assert!(Decimal::parse("1000000000000000000").is_err());
+        assert!(Decimal::parse("abc").is_err());
+    }
+}
@@ -0,0 +1,586 @@
+use crate::cache::{AccountCache, CachedSignature, UpdateRecord};
+use crate::pyth::{self, PriceAccount, PythClient};
+use crate::twap::PriceUpdate;
+use crate::window::{Position, Window};
+use solana_client::rpc_client::GetConfirmedSignaturesForAddress2Config;
+use solana_program::pubkey::Pubkey;
+use solana_sdk::signature::Signature;
+use solana_transaction_status::{EncodedConfirmedTransaction, UiTransactionEncoding};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+pub const DEFAULT_CONCURRENCY: usize = 8;
+
+// the rpc node returns up to 1000 signatures per page, the pager may run a page ahead
+const SIGNATURE_PAGE_SIZE: usize = 1000;
+
+// a signature inside the window, numbered in the order the rpc node returned it
+struct Job {
+    index: usize,
+    signature: String,
+    slot: u64,
+    block_time: i64,
+}
+
+// the decoded updates of one transaction, or why it could not be fetched
+struct Fetched {
+    index: usize,
+    signature: String,
+    slot: u64,
+    block_time: i64,
+    // read from the cache rather than fetched
+    cached: bool,
+    updates: Result<Vec<PriceUpdate>, &'static str>,
+}
+
+// fetch and decode every price account transaction in the window
+// one thread pages through the signatures ahead of a pool of workers fetching the transactions,
+// the updates come back in signature order so the result is the same as a sequential walk
+// with a cache, only the signatures around its run are paged and only transactions it does
+// not hold are fetched, the cache is brought up to date once the window was walked
+pub fn fetch_updates<F>(
+    pyth: &Arc<PythClient>,
+    price_account: &PriceAccount,
+    window: Window,
+    concurrency: usize,
+    mut cache: Option<&mut AccountCache>,
+    debug: bool,
+    mut progress: F,
+) -> Result<Vec<PriceUpdate>, &'static str>
+where
+    F: FnMut(f32),
+{
+    let stop = Arc::new(AtomicBool::new(false));
+    let (job_tx, job_rx) = mpsc::sync_channel::<Job>(SIGNATURE_PAGE_SIZE);
+    let (result_tx, result_rx) = mpsc::channel::<Fetched>();
+
+    let use_cache = cache.is_some();
+    let cached = match cache.as_deref_mut() {
+        Some(cache) => std::mem::take(cache),
+        None => AccountCache::new(&price_account.key),
+    };
+    let pager = {
+        let pyth = Arc::clone(pyth);
+        let stop = Arc::clone(&stop);
+        let key = price_account.key;
+        let mut queue = Queue {
+            window,
+            index: 0,
+            jobs: job_tx,
+            results: result_tx.clone(),
+        };
+        thread::spawn(move || {
+            let walked = page_signatures(&pyth, &key, debug, &stop, &cached, &mut queue);
+            (cached, walked)
+        })
+    };
+
+    let workers = {
+        let pyth = Arc::clone(pyth);
+        let program_id = price_account.program_id;
+        let key = price_account.key;
+        spawn_workers(concurrency, job_rx, &result_tx, &stop, move |job| {
+            fetch_transaction(&pyth, job, &program_id, &key)
+        })
+    };
+    // once the workers and the pager are gone the results run dry
+    drop(result_tx);
+
+    let Collected {
+        fetched,
+        fresh,
+        mut error,
+    } = collect(result_rx, &stop, window, use_cache, &mut progress);
+    // the pager only fails once the rpc retries for a signature page are used up
+    let mut walked = None;
+    let mut cached = None;
+    if let Ok((c, w)) = pager.join() {
+        match w {
+            Ok(w) => walked = w,
+            Err(e) => {
+                error.get_or_insert(e);
+            }
+        }
+        cached = Some(c);
+    }
+    for worker in workers {
+        let _ = worker.join();
+    }
+
+    // the cache is handed back as it was unless the whole window was walked
+    if let (Some(cache), Some(mut cached)) = (cache, cached) {
+        if let (None, Some(walked)) = (error, walked) {
+            cached.signatures = walked;
+            cached.transactions.extend(fresh);
+        }
+        *cache = cached;
+    }
+    if let Some(e) = error {
+        return Err(e);
+    }
+    Ok(fetched.into_values().flatten().collect())
+}
+
+// a pool of workers fetching the queued jobs until the queue closes or stop is set
+fn spawn_workers<F>(
+    concurrency: usize,
+    jobs: Receiver<Job>,
+    results: &Sender<Fetched>,
+    stop: &Arc<AtomicBool>,
+    fetch: F,
+) -> Vec<JoinHandle<()>>
+where
+    F: Fn(&Job) -> Result<Vec<PriceUpdate>, &'static str> + Send + Sync + 'static,
+{
+    let jobs = Arc::new(Mutex::new(jobs));
+    let fetch = Arc::new(fetch);
+    let mut workers = Vec::with_capacity(concurrency.max(1));
+    for _ in 0..concurrency.max(1) {
+        let stop = Arc::clone(stop);
+        let jobs = Arc::clone(&jobs);
+        let results = results.clone();
+        let fetch = Arc::clone(&fetch);
+        workers.push(thread::spawn(move || loop {
+            if stop.load(Ordering::Relaxed) {
+                break;
+            }
+            // the lock is only held while waiting for the next job
+            let job = match jobs.lock().unwrap().recv() {
+                Ok(job) => job,
+                Err(_) => break,
+            };
+            let updates = fetch(&job);
+            let fetched = Fetched {
+                index: job.index,
+                signature: job.signature,
+                slot: job.slot,
+                block_time: job.block_time,
+                cached: false,
+                updates,
+            };
+            if results.send(fetched).is_err() {
+                break;
+            }
+        }));
+    }
+    workers
+}
+
+struct Collected {
+    // by signature index
+    fetched: BTreeMap<usize, Vec<PriceUpdate>>,
+    // fetched rather than read from the cache, to add to it
+    fresh: Vec<(String, Vec<UpdateRecord>)>,
+    error: Option<&'static str>,
+}
+
+// gather the results until every sender is gone, setting stop on the first error
+fn collect<F>(
+    results: Receiver<Fetched>,
+    stop: &AtomicBool,
+    window: Window,
+    keep_fresh: bool,
+    progress: &mut F,
+) -> Collected
+where
+    F: FnMut(f32),
+{
+    let mut fetched = BTreeMap::new();
+    let mut fresh = Vec::new();
+    let mut error = None;
+    let mut done: f32 = 0.0;
+    for f in results {
+        match f.updates {
+            Ok(updates) => {
+                if keep_fresh && !f.cached {
+                    fresh.push((f.signature, updates.iter().map(UpdateRecord::new).collect()));
+                }
+                fetched.insert(f.index, updates);
+            }
+            Err(e) => {
+                // wind the pipeline down, the first error is the one reported
+                stop.store(true, Ordering::Relaxed);
+                error.get_or_insert(e);
+            }
+        }
+        // transactions finish out of order, the progress never goes backwards
+        done = done.max(window.progress(f.slot, f.block_time));
+        progress(done);
+    }
+    Collected {
+        fetched,
+        fresh,
+        error,
+    }
+}
+
+// hands the signatures inside the window to the workers, or straight to the results when cached
+struct Queue {
+    window: Window,
+    index: usize,
+    jobs: SyncSender<Job>,
+    results: Sender<Fetched>,
+}
+
+enum Step {
+    Next,
+    // the signature is older than the window, so is every one after it
+    Older,
+    // the workers have stopped after an error
+    Stop,
+}
+
+impl Queue {
+    fn visit(&mut self, sig: &CachedSignature, cached: &AccountCache, debug: bool) -> Step {
+        if sig.failed {
+            if debug {
+                println!("{}: Sig Err: {}", sig.slot, sig.signature);
+            }
+            return Step::Next;
+        }
+        // check the signature against the window
+        let block_time = match sig.block_time {
+            Some(t) => t,
+            None => return Step::Next,
+        };
+        match self.window.locate(sig.slot, block_time) {
+            // signatures come newest first, skip ahead to the end of the window
+            Position::Newer => return Step::Next,
+            Position::Older => return Step::Older,
+            Position::Inside => {}
+        }
+        let index = self.index;
+        self.index += 1;
+        // a cache entry that does not decode is fetched again
+        let updates = cached
+            .transactions
+            .get(&sig.signature)
+            .and_then(|records| records.iter().map(|r| r.update().ok()).collect());
+        if let Some(updates) = updates {
+            let fetched = Fetched {
+                index,
+                signature: sig.signature.clone(),
+                slot: sig.slot,
+                block_time,
+                cached: true,
+                updates: Ok(updates),
+            };
+            if self.results.send(fetched).is_err() {
+                return Step::Stop;
+            }
+            return Step::Next;
+        }
+        let job = Job {
+            index,
+            signature: sig.signature.clone(),
+            slot: sig.slot,
+            block_time,
+        };
+        if self.jobs.send(job).is_err() {
+            return Step::Stop;
+        }
+        Step::Next
+    }
+    // visit a page of signatures until one is older than the window, keeping all of them
+    // returns false when the workers stopped early
+    fn walk(
+        &mut self,
+        page: Vec<CachedSignature>,
+        cached: &AccountCache,
+        debug: bool,
+        older: &mut bool,
+        walked: &mut Vec<CachedSignature>,
+    ) -> bool {
+        for sig in page {
+            if !*older {
+                match self.visit(&sig, cached, debug) {
+                    Step::Next => {}
+                    Step::Older => *older = true,
+                    Step::Stop => return false,
+                }
+            }
+            walked.push(sig);
+        }
+        true
+    }
+}
+
+// walk the price account signatures newest first and queue the ones inside the window
+// the signatures newer than the cached run are paged down to it with until, then the run is
+// walked without any rpc calls, then the signatures older than it are paged with before
+// returns every signature walked, a run without holes to replace the cached one,
+// or None when the workers stopped early
+fn page_signatures(
+    pyth: &PythClient,
+    key: &Pubkey,
+    debug: bool,
+    stop: &AtomicBool,
+    cached: &AccountCache,
+    queue: &mut Queue,
+) -> Result<Option<Vec<CachedSignature>>, &'static str> {
+    let run = &cached.signatures;
+    let mut walked = Vec::new();
+    let mut older = false;
+
+    let until = run.first().map(|s| s.signature.clone());
+    let mut before = None;
+    loop {
+        if stop.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let page = signature_page(pyth, key, before.as_deref(), until.as_deref(), debug)?;
+        // walked past the oldest signature of the account, or reached the cached run
+        let last = match page.last() {
+            Some(last) => last.signature.clone(),
+            None => break,
+        };
+        before = Some(last);
+        if !queue.walk(page, cached, debug, &mut older, &mut walked) {
+            return Ok(None);
+        }
+        // without a run to reach there is no need to page past the window
+        if older && until.is_none() {
+            return Ok(Some(walked));
+        }
+        if debug {
+            println!("getting next batch of transactions");
+        }
+    }
+    // with nothing cached every signature the account has was walked
+    if until.is_none() {
+        return Ok(Some(walked));
+    }
+
+    for sig in run {
+        if older {
+            break;
+        }
+        match queue.visit(sig, cached, debug) {
+            Step::Next => {}
+            Step::Older => older = true,
+            Step::Stop => return Ok(None),
+        }
+    }
+    walked.extend(run.iter().cloned());
+
+    before = run.last().map(|s| s.signature.clone());
+    while !older {
+        if stop.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let page = signature_page(pyth, key, before.as_deref(), None, debug)?;
+        let last = match page.last() {
+            Some(last) => last.signature.clone(),
+            None => break,
+        };
+        before = Some(last);
+        if !queue.walk(page, cached, debug, &mut older, &mut walked) {
+            return Ok(None);
+        }
+    }
+    Ok(Some(walked))
+}
+
+// one page of signatures newest first, before and until are exclusive
+fn signature_page(
+    pyth: &PythClient,
+    key: &Pubkey,
+    before: Option<&str>,
+    until: Option<&str>,
+    debug: bool,
+) -> Result<Vec<CachedSignature>, &'static str> {
+    let parse = |s: Option<&str>| match s.map(Signature::from_str) {
+        Some(Ok(sig)) => Ok(Some(sig)),
+        Some(Err(_)) => Err("not a valid transaction signature"),
+        None => Ok(None),
+    };
+    let (before, until) = (parse(before)?, parse(until)?);
+    let px_sigs = pyth.call(|c| {
+        let rqt_config = GetConfirmedSignaturesForAddress2Config {
+            before,
+            until,
+            limit: None,
+            commitment: None,
+        };
+        c.get_signatures_for_address_with_config(key, rqt_config)
+    });
+    match px_sigs {
+        Ok(result) => Ok(result
+            .into_iter()
+            .map(|sig| CachedSignature {
+                signature: sig.signature,
+                slot: sig.slot,
+                block_time: sig.block_time,
+                failed: sig.err.is_some(),
+            })
+            .collect()),
+        Err(error) => {
+            if debug {
+                println!("Rpc Err: {}", error);
+            }
+            Err("error getting price account signatures")
+        }
+    }
+}
+
+fn fetch_transaction(
+    pyth: &PythClient,
+    job: &Job,
+    program_id: &Pubkey,
+    price_key: &Pubkey,
+) -> Result<Vec<PriceUpdate>, &'static str> {
+    let txn = get_transaction(pyth, &job.signature)?;
+    Ok(decode_updates(
+        &txn,
+        job.slot,
+        job.block_time,
+        program_id,
+        price_key,
+    ))
+}
+
+fn get_transaction(
+    pyth: &PythClient,
+    signature: &str,
+) -> Result<EncodedConfirmedTransaction, &'static str> {
+    let signature = match Signature::from_str(signature) {
+        Ok(signature) => signature,
+        Err(_) => return Err("not a valid transaction signature"),
+    };
+    // request transaction from signature
+    match pyth.call(|c| c.get_transaction(&signature, UiTransactionEncoding::Base64)) {
+        Ok(txn) => Ok(txn),
+        Err(_) => Err("error getting price account transaction"),
+    }
+}
+
+// the price updates of a fetched transaction, stamped with the slot and time it landed at
+pub fn decode_updates(
+    txn: &EncodedConfirmedTransaction,
+    slot: u64,
+    block_time: i64,
+    program_id: &Pubkey,
+    price_key: &Pubkey,
+) -> Vec<PriceUpdate> {
+    let t = match txn.transaction.transaction.decode() {
+        Some(t) => t,
+        None => return Vec::new(),
+    };
+    // publishers batch updates and add compute budget instructions,
+    // only updates sent to the pyth program for this price account count
+    pyth::price_updates(&t.message, program_id, price_key)
+        .into_iter()
+        .map(|(publisher, data)| PriceUpdate {
+            pub_slot: data.pub_slot,
+            slot,
+            block_time,
+            price: data.price,
+            conf: data.conf,
+            status: data.status,
+            publisher,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fetch::{collect, spawn_workers, Fetched, Job};
+    use crate::pyth::Status;
+    use crate::twap::PriceUpdate;
+    use crate::window::Window;
+    use solana_program::pubkey::Pubkey;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::mpsc;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn job(index: usize) -> Job {
+        Job {
+            index,
+            signature: format!("sig{}", index),
+            slot: 100 - index as u64,
+            block_time: 1000 - index as i64,
+        }
+    }
+
+    // runs the pool over jobs 0..count and collects the results, on another thread so
+    // a hang fails the test instead of blocking it
+    fn run<F>(count: usize, concurrency: usize, fetch: F) -> (Vec<i64>, Option<&'static str>)
+    where
+        F: Fn(&Job) -> Result<Vec<PriceUpdate>, &'static str> + Send + Sync + 'static,
+    {
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let stop = Arc::new(AtomicBool::new(false));
+            let (job_tx, job_rx) = mpsc::sync_channel::<Job>(1);
+            let (result_tx, result_rx) = mpsc::channel::<Fetched>();
+            let workers = spawn_workers(concurrency, job_rx, &result_tx, &stop, fetch);
+            drop(result_tx);
+            // stops queueing once the workers are gone, like the pager
+            let pager = thread::spawn(move || {
+                for index in 0..count {
+                    if job_tx.send(job(index)).is_err() {
+                        break;
+                    }
+                }
+            });
+            let window = Window::slots(1, 100).unwrap();
+            let collected = collect(result_rx, &stop, window, true, &mut |_| {});
+            pager.join().unwrap();
+            for worker in workers {
+                worker.join().unwrap();
+            }
+            let prices = collected
+                .fetched
+                .into_values()
+                .flatten()
+                .map(|u| u.price)
+                .collect();
+            let _ = done_tx.send((prices, collected.error));
+        });
+        done_rx
+            .recv_timeout(Duration::from_secs(10))
+            .expect("the pool did not shut down")
+    }
+
+    fn update(price: i64) -> PriceUpdate {
+        PriceUpdate {
+            pub_slot: 1,
+            slot: 1,
+            block_time: 1,
+            price,
+            conf: 1,
+            status: Status::Trading,
+            publisher: Pubkey::default(),
+        }
+    }
+
+    #[test]
+    fn keeps_signature_order() {
+        // the first jobs take the longest, so they finish last
+        let (prices, error) = run(8, 4, |job| {
+            thread::sleep(Duration::from_millis(40 - 5 * job.index as u64));
+            Ok(vec![update(job.index as i64)])
+        });
+        assert_eq!(error, None);
+        assert_eq!(prices, (0..8).collect::<Vec<i64>>());
+    }
+    #[test]
+    fn stops_on_the_first_error() {
+        let (prices, error) = run(1000, 4, |job| {
+            if job.index == 3 {
+                return Err("error getting price account transaction");
+            }
+            thread::sleep(Duration::from_millis(1));
+            Ok(vec![update(job.index as i64)])
+        });
+        assert_eq!(error, Some("error getting price account transaction"));
+        // the pool wound down instead of working through the queue
+        assert!(prices.len() < 999);
+    }
+}
@@ -0,0 +1,191 @@
+use crate::decimal::Decimal;
+use crate::twap::{PriceUpdate, TwapAccumulator};
+
+pub const DEFAULT_MEDIAN_WINDOW: usize = 21;
+
+// rules for dropping bad publisher updates before anything is aggregated
+// every rule is off unless set, the first rule an update breaks is the reason it is dropped
+pub struct OutlierFilter {
+    // drop updates whose conf / price is above this ratio
+    pub max_conf_ratio: Option<Decimal>,
+    // drop updates more than k times their own conf away from the rolling median
+    pub conf_k: Option<Decimal>,
+    // drop updates more than k median absolute deviations away from the rolling median
+    pub mad_k: Option<Decimal>,
+    // number of updates (centered on the one checked) the rolling median is taken over
+    pub median_window: usize,
+}
+
+impl Default for OutlierFilter {
+    fn default() -> OutlierFilter {
+        OutlierFilter {
+            max_conf_ratio: None,
+            conf_k: None,
+            mad_k: None,
+            median_window: DEFAULT_MEDIAN_WINDOW,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Rejections {
+    pub conf_ratio: usize,
+    pub conf_band: usize,
+    pub mad: usize,
+}
+
+impl Rejections {
+    pub fn total(&self) -> usize {
+        self.conf_ratio + self.conf_band + self.mad
+    }
+}
+
+impl OutlierFilter {
+    pub fn is_enabled(&self) -> bool {
+        self.max_conf_ratio.is_some() || self.conf_k.is_some() || self.mad_k.is_some()
+    }
+    // the updates that pass every rule, and how many each rule rejected
+    pub fn apply(
+        &self,
+        acc: &TwapAccumulator,
+    ) -> Result<(TwapAccumulator, Rejections), &'static str> {
+        // the limits come from the command line, a product that does not fit is an error
+        let times = |a: Decimal, b: &Decimal| match a.checked_mul(b) {
+            Some(product) => Ok(product),
+            None => Err("outlier limit is too large"),
+        };
+        let mut kept = TwapAccumulator::new(acc.expo());
+        let mut rejections = Rejections::default();
+        let updates: Vec<&PriceUpdate> = acc.updates().collect();
+        let half = self.median_window.max(1) / 2;
+
+        for (i, u) in updates.iter().enumerate() {
+            let price = Decimal::from_price(u.price, 0);
+            let conf = Decimal::new(u.conf as i128, 0);
+            if let Some(ratio) = &self.max_conf_ratio {
+                if conf > times(price.abs(), ratio)? {
+                    rejections.conf_ratio += 1;
+                    continue;
+                }
+            }
+
+            if self.conf_k.is_some() || self.mad_k.is_some() {
+                let from = i.saturating_sub(half);
+                let to = (i + half + 1).min(updates.len());
+                // widened so publisher prices at the ends of the i64 range do not overflow
+                let mut prices: Vec<i128> =
+                    updates[from..to].iter().map(|u| u.price as i128).collect();
+                let mid = median(&mut prices);
+                let deviation = Decimal::new((u.price as i128 - mid).abs(), 0);
+
+                if let Some(k) = &self.conf_k {
+                    if deviation > times(conf, k)? {
+                        rejections.conf_band += 1;
+                        continue;
+                    }
+                }
+                if let Some(k) = &self.mad_k {
+                    let mut deviations: Vec<i128> =
+                        prices.iter().map(|p| (*p - mid).abs()).collect();
+                    let mad = Decimal::new(median(&mut deviations), 0);
+                    // a flat market has no spread to measure against
+                    if !mad.is_zero() && deviation > times(mad, k)? {
+                        rejections.mad += 1;
+                        continue;
+                    }
+                }
+            }
+            kept.ingest((*u).clone());
+        }
+        Ok((kept, rejections))
+    }
+}
+
+// upper median, the values are reordered
+fn median(values: &mut [i128]) -> i128 {
+    values.sort_unstable();
+    values[values.len() / 2]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decimal::Decimal;
+    use crate::filter::{OutlierFilter, Rejections};
+    use crate::pyth::Status;
+    use crate::twap::{PriceUpdate, TwapAccumulator};
+    use solana_program::pubkey::Pubkey;
+
+    fn accumulate(prices: &[(i64, u64)]) -> TwapAccumulator {
+        let mut acc = TwapAccumulator::new(0);
+        for (i, (price, conf)) in prices.iter().enumerate() {
+            acc.ingest(PriceUpdate {
+                pub_slot: i as u64,
+                slot: i as u64,
+                block_time: i as i64,
+                price: *price,
+                conf: *conf,
+                status: Status::Trading,
+                publisher: Pubkey::default(),
+            });
+        }
+        acc
+    }
+
+    #[test]
+    fn disabled_keeps_everything() {
+        let acc = accumulate(&[(100, 1), (1000, 1), (101, 1)]);
+        let filter = OutlierFilter::default();
+        assert!(!filter.is_enabled());
+        let (kept, rejections) = filter.apply(&acc).unwrap();
+        assert_eq!(kept.count(), 3);
+        assert_eq!(rejections.total(), 0);
+    }
+    #[test]
+    fn rejects_wide_conf() {
+        let acc = accumulate(&[(100, 1), (100, 20), (101, 1)]);
+        let filter = OutlierFilter {
+            max_conf_ratio: Some(Decimal::parse("0.1").unwrap()),
+            ..OutlierFilter::default()
+        };
+        let (kept, rejections) = filter.apply(&acc).unwrap();
+        assert_eq!(kept.count(), 2);
+        assert_eq!(rejections.conf_ratio, 1);
+    }
+    #[test]
+    fn rejects_spikes() {
+        let acc = accumulate(&[(100, 2), (102, 2), (1000, 2), (101, 2), (99, 2)]);
+        let by_conf = OutlierFilter {
+            conf_k: Some(Decimal::new(3, 0)),
+            ..OutlierFilter::default()
+        };
+        let (kept, rejections) = by_conf.apply(&acc).unwrap();
+        assert_eq!(kept.ohlc().unwrap().high, Decimal::new(102, 0));
+        assert_eq!(
+            rejections,
+            Rejections {
+                conf_ratio: 0,
+                conf_band: 1,
+                mad: 0
+            }
+        );
+
+        let by_mad = OutlierFilter {
+            mad_k: Some(Decimal::new(5, 0)),
+            ..OutlierFilter::default()
+        };
+        let (kept, rejections) = by_mad.apply(&acc).unwrap();
+        assert_eq!(kept.count(), 4);
+        assert_eq!(rejections.mad, 1);
+    }
+    #[test]
+    fn handles_extreme_prices() {
+        let acc = accumulate(&[(-5, 1), (i64::MAX, 1), (-6, 1), (-5, 1)]);
+        let by_mad = OutlierFilter {
+            mad_k: Some(Decimal::new(5, 0)),
+            ..OutlierFilter::default()
+        };
+        let (kept, rejections) = by_mad.apply(&acc).unwrap();
+        assert_eq!(kept.count(), 3);
+        assert_eq!(rejections.mad, 1);
+    }
+}
@@ -0,0 +1,139 @@
+use crate::pyth::{ProductResult, PythAccount, PythProduct};
+use pyth_client::Product;
+use solana_program::pubkey::Pubkey;
+use std::collections::BTreeMap;
+
+// get_multiple_accounts takes at most this many keys per call
+pub const MAX_MULTIPLE_ACCOUNTS: usize = 100;
+
+pub struct ProductEntry {
+    pub key: Pubkey,
+    // the first price account of the product, None if the product lists an invalid one
+    pub price_key: Option<Pubkey>,
+}
+
+// every product the mapping accounts list, by symbol
+// load it once with PythClient::load_index and look up as many symbols as needed
+#[derive(Default)]
+pub struct ProductIndex {
+    products: BTreeMap<String, ProductEntry>,
+    // listed products whose account was missing or not a valid product
+    pub skipped: usize,
+}
+
+impl ProductIndex {
+    // add the product account at key, a missing or invalid account is counted and skipped
+    pub fn insert(&mut self, key: Pubkey, data: Option<&[u8]>) {
+        let prod_acct = match data.and_then(|d| Product::new::<Product>(d)) {
+            Some(prod_acct) if prod_acct.is_valid() => prod_acct,
+            _ => {
+                self.skipped += 1;
+                return;
+            }
+        };
+        let symbol = match prod_acct.get_symbol() {
+            Some(symbol) => symbol,
+            None => {
+                self.skipped += 1;
+                return;
+            }
+        };
+        let price_key = if prod_acct.px_acc.is_valid() {
+            Some(Pubkey::new(&prod_acct.px_acc.val))
+        } else {
+            None
+        };
+        // the first product listed for a symbol wins, like a linear search would
+        self.products
+            .entry(symbol)
+            .or_insert(ProductEntry { key, price_key });
+    }
+    pub fn get(&self, symbol: &str) -> Result<ProductResult, &'static str> {
+        let entry = match self.products.get(symbol) {
+            Some(entry) => entry,
+            None => return Err("product account not found"),
+        };
+        match entry.price_key {
+            Some(price_key) => Ok(ProductResult {
+                key: entry.key,
+                price_accounts: price_key.to_bytes(),
+            }),
+            None => Err("pyth price account in valid"),
+        }
+    }
+    pub fn symbols(&self) -> impl Iterator<Item = &String> {
+        self.products.keys()
+    }
+    pub fn len(&self) -> usize {
+        self.products.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.products.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::index::ProductIndex;
+    use pyth_client::{AccKey, AccountType, Product, MAGIC, PROD_ATTR_SIZE, VERSION_2};
+    use solana_program::pubkey::Pubkey;
+    use std::mem::size_of;
+
+    fn product_data(symbol: &str, price_key: [u8; 32]) -> Vec<u8> {
+        let mut attr = [0u8; PROD_ATTR_SIZE];
+        attr[0] = 6;
+        attr[1..7].copy_from_slice(b"symbol");
+        attr[7] = symbol.len() as u8;
+        attr[8..8 + symbol.len()].copy_from_slice(symbol.as_bytes());
+        let product = Product {
+            magic: MAGIC,
+            ver: VERSION_2,
+            atype: AccountType::Product as u32,
+            size: (48 + 8 + symbol.len()) as u32,
+            px_acc: AccKey { val: price_key },
+            attr,
+        };
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                &product as *const Product as *const u8,
+                size_of::<Product>(),
+            )
+        };
+        bytes.to_vec()
+    }
+
+    #[test]
+    fn indexes_by_symbol() {
+        let eth = Pubkey::new(&[1; 32]);
+        let btc = Pubkey::new(&[2; 32]);
+        let mut index = ProductIndex::default();
+        index.insert(eth, Some(&product_data("ETH/USD", [9; 32])));
+        index.insert(Pubkey::new(&[3; 32]), None);
+        index.insert(Pubkey::new(&[4; 32]), Some(&[0; 16]));
+        index.insert(btc, Some(&product_data("BTC/USD", [0; 32])));
+
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.skipped, 2);
+        let product = index.get("ETH/USD").unwrap();
+        assert_eq!(product.key, eth);
+        assert_eq!(product.price_accounts, [9; 32]);
+        // listed with an empty price account key
+        assert!(index.get("BTC/USD").is_err());
+        assert!(index.get("DOGE/USD").is_err());
+    }
+    #[test]
+    fn skips_truncated_products() {
+        let eth = Pubkey::new(&[1; 32]);
+        let mut index = ProductIndex::default();
+        // a size below the header, one that cuts the symbol short and one past the account
+        for size in [10u32, 48 + 3, u32::MAX] {
+            let mut data = product_data("BTC/USD", [9; 32]);
+            data[12..16].copy_from_slice(&size.to_le_bytes());
+            index.insert(Pubkey::new(&[2; 32]), Some(&data));
+        }
+        index.insert(eth, Some(&product_data("ETH/USD", [9; 32])));
+        assert_eq!(index.skipped, 3);
+        assert_eq!(index.get("ETH/USD").unwrap().key, eth);
+        assert!(index.get("BTC/USD").is_err());
+    }
+}
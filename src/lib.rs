@@ -0,0 +1,26 @@
+pub mod aggregate;
+pub mod cache;
+pub mod candle;
+pub mod coverage;
+pub mod decimal;
+pub mod fetch;
+pub mod filter;
+pub mod index;
+pub mod nonblocking;
+pub mod publisher;
+pub mod pyth;
+pub mod report;
+pub mod retry;
+pub mod source;
+pub mod status;
+pub mod twap;
+pub mod watch;
+pub mod window;
+
+pub use crate::decimal::Decimal;
+pub use crate::nonblocking::AsyncPythClient;
+pub use crate::pyth::PythClient;
+pub use crate::report::{
+    compute_twap, compute_twap_from, OnChainComparison, TwapOptions, TwapReport,
+};
+pub use crate::window::Window;
@@ -1,175 +1,447 @@
 mod config;
-mod pyth;
-use crate::pyth::{PythAccount, PythClient, UpdatePriceInstruction};
-use chrono::prelude::DateTime;
-use chrono::Utc;
-use progress_bar::color::{Color, Style};
-use progress_bar::progress_bar::ProgressBar;
-use solana_client::rpc_client::GetConfirmedSignaturesForAddress2Config;
-use solana_sdk::signature::Signature;
-use solana_transaction_status::UiTransactionEncoding;
+use chrono::{TimeZone, Utc};
+use config::{CacheAction, CacheConfig, Command, Config, InspectConfig, WatchConfig};
+use pyth_twap::cache::Cache;
+use pyth_twap::candle;
+use pyth_twap::pyth::Status;
+use pyth_twap::report::MAX_ONCHAIN_LAG;
+use pyth_twap::source::{FileSource, PriceUpdateSource, RpcSource};
+use pyth_twap::twap::Method;
+use pyth_twap::watch::{self, AccountSubscription, PriceFeed, RollingSnapshot, RollingTwap};
+use pyth_twap::{
+    compute_twap, compute_twap_from, Decimal, PythClient, TwapOptions, TwapReport, Window,
+};
+use std::fs;
+use std::path::Path;
 use std::process;
-use std::str::FromStr;
-use std::time::{Duration as StdDuration, UNIX_EPOCH};
+use std::sync::Arc;
+use std::time::Duration;
 
 fn main() {
-    let c = config::Config::new().unwrap_or_else(|err| {
+    let command = Command::new().unwrap_or_else(|err| {
         println!("Config Err: {:?}", err);
         process::exit(1);
     });
+    match command {
+        Command::Twap(c) => twap(*c),
+        Command::InspectTx(c) => inspect_tx(c),
+        Command::Cache(c) => cache(c),
+        Command::Watch(c) => watch_price(c),
+    }
+}
 
-    let pyth = PythClient::new(&c.url).unwrap();
-    println!("{:.<20} {}", "mapping_account", &c.pyth_key);
-
-    let product_account = match pyth.get_product_account(&c.pyth_key, &c.symbol) {
-        Ok(product_account) => product_account,
-        Err(error) => {
-            println!("Pyth Err: {:?}", error);
-            return;
+fn cache(c: CacheConfig) {
+    let cache = Cache::new(&c.dir);
+    let result = match c.action {
+        CacheAction::Inspect => cache.accounts().map(|accounts| {
+            if accounts.is_empty() {
+                println!("Nothing cached");
+            }
+            let time = |t: Option<i64>| match t.and_then(|t| Utc.timestamp_opt(t, 0).single()) {
+                Some(t) => t.to_rfc3339(),
+                None => "unknown time".to_string(),
+            };
+            for (account, size) in accounts {
+                println!();
+                println!("{:.<20} {}", "price_account", account.price_key);
+                println!("{:.<20} {}", "signatures", account.signatures.len());
+                println!("{:.<20} {}", "transactions", account.transactions.len());
+                println!("{:.<20} {}", "updates", account.num_updates());
+                if let (Some(oldest), Some(newest)) = (account.oldest(), account.newest()) {
+                    println!(
+                        "{:.<20} {} ({})",
+                        "oldest",
+                        oldest.slot,
+                        time(oldest.block_time)
+                    );
+                    println!(
+                        "{:.<20} {} ({})",
+                        "newest",
+                        newest.slot,
+                        time(newest.block_time)
+                    );
+                }
+                println!("{:.<20} {} bytes", "size", size);
+            }
+        }),
+        CacheAction::Prune { older_than } => {
+            let before = Utc::now().timestamp() - older_than;
+            cache.prune(before).map(|dropped| {
+                println!(
+                    "Pruned {} signature(s) older than {}",
+                    dropped,
+                    Utc.timestamp_opt(before, 0).unwrap().to_rfc3339()
+                );
+            })
         }
+        CacheAction::Clear => cache.clear().map(|removed| {
+            println!("Removed {} cached price account(s)", removed);
+        }),
     };
-    println!("{:.<20} {}", "product_account", &product_account.key);
+    if let Err(error) = result {
+        println!("Cache Err: {:?}", error);
+        process::exit(1);
+    }
+}
 
-    let price_account = match pyth.get_price_account(product_account.price_accounts) {
-        Ok(price_account) => price_account,
+fn inspect_tx(c: InspectConfig) {
+    let pyth = PythClient::new(&c.url).unwrap_or_else(|err| {
+        println!("Pyth Err: {:?}", err);
+        process::exit(1);
+    });
+    let (slot, instructions) = match pyth.inspect_transaction(&c.signature, &c.program_id) {
+        Ok(result) => result,
         Err(error) => {
             println!("Pyth Err: {:?}", error);
-            return;
+            process::exit(1);
         }
     };
+    println!("{:.<20} {}", "slot", slot);
+    if instructions.is_empty() {
+        println!("No instructions for pyth program {}", c.program_id);
+        return;
+    }
+    for decoded in instructions {
+        println!();
+        match &decoded.instruction {
+            Ok(instruction) => {
+                println!("#{} {}", decoded.index, instruction);
+                let roles = instruction.account_roles();
+                for (i, account) in decoded.accounts.iter().enumerate() {
+                    let role = roles.get(i).unwrap_or(&"extra");
+                    println!("  {:.<18} {}", role, account);
+                }
+            }
+            Err(error) => {
+                println!("#{} undecodable: {}", decoded.index, error);
+                for account in &decoded.accounts {
+                    println!("  {}", account);
+                }
+            }
+        }
+    }
+}
+
+fn watch_price(c: WatchConfig) {
+    println!("{:.<20} {}", "mapping_account", &c.pyth_key);
+    let exit = |error: &'static str| -> ! {
+        println!("Pyth Err: {:?}", error);
+        process::exit(1);
+    };
+    let pyth = Arc::new(PythClient::new(&c.url).unwrap_or_else(|err| exit(err)));
+    let product_account = pyth
+        .get_product_account(&c.pyth_key, &c.symbol)
+        .unwrap_or_else(|err| exit(err));
+    let price_account = pyth
+        .get_price_account(product_account.price_accounts)
+        .unwrap_or_else(|err| exit(err));
+    println!("{:.<20} {}", "product_account", product_account.key);
     println!("{:.<20} {}", "price_account", price_account.key);
 
-    println!("");
-    println!("Parsing price account transactions");
-    let mut progress_bar = ProgressBar::new(100);
-    progress_bar.set_action(" Progress", Color::Blue, Style::Bold);
+    // seed the rolling window with the aggregates of the last interval
+    let mut source = RpcSource::new(Arc::clone(&pyth));
+    source.cache = c.cache_dir.as_ref().map(Cache::new);
+    source.debug = c.debug;
+    source.progress = true;
+    let window = Window::last(chrono::Duration::seconds(c.interval));
+    let history = source
+        .updates(&price_account, &window)
+        .unwrap_or_else(|err| exit(err));
+    let mut rolling = RollingTwap::new(c.interval, price_account.expo);
+    for aggregate in watch::history(&history, price_account.expo) {
+        rolling.push(aggregate);
+    }
+    println!();
+    println!("Seeded with {} aggregate(s)", rolling.len());
+    if let Some(snapshot) = rolling.snapshot(Utc::now().timestamp()) {
+        print_snapshot(&snapshot);
+    }
 
-    // Loop through transactions and get last N transactions over the given interval
-    let start_t = Utc::now();
-    let end_t = start_t - c.interval;
-    let interval_microseconds = c.interval.num_microseconds().unwrap();
+    let mut feed = match c.poll {
+        Some(secs) => PriceFeed::Poll {
+            pyth: Arc::clone(&pyth),
+            key: price_account.key,
+            every: Duration::from_secs(secs),
+        },
+        None => PriceFeed::Subscribe(Box::new(
+            AccountSubscription::connect(&c.ws_url, &price_account.key, pyth.retry.clone())
+                .unwrap_or_else(|err| exit(err)),
+        )),
+    };
+    loop {
+        let account = feed.recv().unwrap_or_else(|err| exit(err));
+        let now = Utc::now().timestamp();
+        let aggregate = watch::aggregate_update(&account, now);
+        if aggregate.status != Status::Trading {
+            if c.debug {
+                println!(
+                    "aggregate at slot {} is {}",
+                    aggregate.pub_slot,
+                    aggregate.status.name()
+                );
+            }
+            continue;
+        }
+        // the same aggregate is seen again when polling faster than it changes
+        if !rolling.push(aggregate) {
+            continue;
+        }
+        if let Some(snapshot) = rolling.snapshot(now) {
+            print_snapshot(&snapshot);
+        }
+    }
+}
 
-    // we can request 1000 sig per req
-    let mut last_sig: Option<Signature> = None;
+fn print_snapshot(snapshot: &RollingSnapshot) {
+    let ohlc = &snapshot.ohlc;
+    println!(
+        "{} slot {} TWAP ${} TWAC ${} O ${} H ${} L ${} C ${} ({} aggregates)",
+        Utc.timestamp_opt(snapshot.end, 0).unwrap().to_rfc3339(),
+        ohlc.close_slot,
+        snapshot.twap,
+        snapshot.twac,
+        ohlc.open,
+        ohlc.high,
+        ohlc.low,
+        ohlc.close,
+        snapshot.count
+    );
+}
 
-    // do we even need to store this?
-    // https://uniswap.org/docs/v2/core-concepts/oracles/
-    let mut open: Option<i64> = None;
-    let mut close: Option<i64> = None;
-    let mut high: Option<i64> = None;
-    let mut low: Option<i64> = None;
-    let mut open_slot: Option<u64> = None;
-    let mut close_slot: Option<u64> = None;
-    'process_px_acct: loop {
-        let rqt_config = GetConfirmedSignaturesForAddress2Config {
-            before: last_sig,
-            until: None,
-            limit: None,
-            commitment: None,
-        };
+// calculate the TWAP from exported updates, with the price account as it was exported
+fn replay(
+    path: &Path,
+    symbol: &str,
+    window: Window,
+    options: &TwapOptions,
+) -> Result<TwapReport, &'static str> {
+    let mut source = FileSource::open(path)?;
+    if source.export.symbol != symbol {
+        return Err("export file is for another symbol");
+    }
+    let product_key = source.export.product_key()?;
+    let price_account = source.export.price_account()?;
+    compute_twap_from(
+        &mut source,
+        symbol,
+        &product_key,
+        &price_account,
+        window,
+        options,
+    )
+}
 
-        let px_sigs = pyth
-            .client
-            .get_signatures_for_address_with_config(&price_account.key, rqt_config);
-        let price_account_signatures = match px_sigs {
-            Ok(result) => result,
-            Err(error) => {
-                println!("Rpc Err: {}", error);
-                continue;
+fn twap(c: Config) {
+    println!("{:.<20} {}", "mapping_account", &c.pyth_key);
+
+    let options = TwapOptions {
+        url: c.url.clone(),
+        mapping_key: c.pyth_key.clone(),
+        method: c.method,
+        candle_size: c.candle_size,
+        max_gap: c.max_gap,
+        min_coverage: c.min_coverage,
+        outliers: c.outliers,
+        status_policy: c.status_policy,
+        reconstruct: c.reconstruct,
+        min_publishers: c.min_publishers,
+        publishers: c.publishers,
+        concurrency: c.concurrency,
+        retry: c.retry.clone(),
+        cache_dir: c.cache_dir.clone(),
+        export: c.export.clone(),
+        debug: c.debug,
+        progress: true,
+    };
+    let report = match &c.replay {
+        Some(path) => replay(path, &c.symbol, c.window, &options),
+        None => compute_twap(&c.symbol, c.window, &options),
+    };
+    let report = match report {
+        Ok(report) => report,
+        Err(error) => {
+            println!("Pyth Err: {:?}", error);
+            process::exit(1);
+        }
+    };
+    println!("{:.<20} {}", "product_account", report.product_key);
+    println!("{:.<20} {}", "price_account", report.price_key);
+
+    println!();
+    println!("TWAP Window: {}", report.window);
+    let ohlc = &report.ohlc;
+    println!("Open: ${} ({})", ohlc.open, ohlc.open_slot);
+    println!("High: ${}", ohlc.high);
+    println!("Low: ${}", ohlc.low);
+    println!("Close: ${} ({})", ohlc.close, ohlc.close_slot);
+    println!("Updates: {}", report.num_updates);
+    let rejections = &report.rejections;
+    if rejections.total() > 0 {
+        println!(
+            "Rejected: {} (conf ratio {}, conf band {}, mad {})",
+            rejections.total(),
+            rejections.conf_ratio,
+            rejections.conf_band,
+            rejections.mad
+        );
+    }
+    println!(
+        "Calculated TWAP Price ({}): ${}",
+        report.method.name(),
+        report.twap
+    );
+    println!("Calculated TWAC: ${}", report.twac);
+    println!(
+        "TWAP Confidence Band: ${} - ${}",
+        report.twap_lower, report.twap_upper
+    );
+
+    match &report.onchain {
+        Some(onchain) => {
+            if report.method != Method::Arithmetic {
+                println!("Calculated TWAP Price (arithmetic): ${}", onchain.calculated);
             }
-        };
-        for sig in price_account_signatures {
-            // check for signature error
-            if let Some(_) = sig.err {
-                if c.debug {
-                    println!("{}: Sig Err: {:?}", sig.slot, sig.err.unwrap());
-                }
-                continue;
-            };
-            // check time duration
-            let block_t = sig.block_time.unwrap() as u64;
-            let block_t = UNIX_EPOCH + StdDuration::from_secs(block_t);
-            let block_t = DateTime::<Utc>::from(block_t);
-            if block_t < end_t {
-                progress_bar.set_progression(100);
-                break 'process_px_acct;
+            println!(
+                "Pyth TWAP Price: ${} (slot {})",
+                onchain.twap, onchain.valid_slot
+            );
+            match &onchain.deviation_pct {
+                Some(pct) => println!("Deviation: ${} ({}%)", onchain.deviation, pct),
+                None => println!("Deviation: ${}", onchain.deviation),
             }
-            // request transaction from signature
-            let s = Signature::from_str(&sig.signature).unwrap();
-            last_sig = Some(s);
-            let txn = pyth
-                .client
-                .get_transaction(&s, UiTransactionEncoding::Base64)
-                .unwrap();
-            let t = txn.transaction.transaction.decode().unwrap(); // transaction
-            let instrs = t.message.instructions;
-            let i = &instrs.first().unwrap(); // first instruction
-            let d = &i.data;
-
-            let data = match UpdatePriceInstruction::new::<UpdatePriceInstruction>(&d) {
-                None => continue, // skip value
-                Some(i) => i,     // unwrap
-            };
-            // check if empty price or invalid status
-            if !data.is_valid() {
+        }
+        None => println!(
+            "Pyth TWAP Price: not compared, the price account changed more than {} slots after the window end",
+            MAX_ONCHAIN_LAG
+        ),
+    }
+    println!("Pyth TWAC: not available, pyth-client 0.2.0 price accounts have no twac");
+    let unit = match report.window {
+        Window::Time { .. } => "s",
+        Window::Slots { .. } => " slots",
+    };
+    if let Some(coverage) = &report.coverage {
+        println!();
+        println!(
+            "Coverage: {}% ({} gap(s))",
+            coverage.pct(),
+            coverage.gaps.len()
+        );
+        let edge = |side: Option<(u64, i64)>| match side {
+            Some((slot, time)) => format!(
+                "{} ({})",
+                slot,
+                Utc.timestamp_opt(time, 0).unwrap().to_rfc3339()
+            ),
+            None => "window edge".to_string(),
+        };
+        for gap in &coverage.gaps {
+            println!(
+                "Gap of {}{}: {} -> {}",
+                gap.length,
+                unit,
+                edge(gap.before),
+                edge(gap.after)
+            );
+        }
+    }
+    let status = &report.status;
+    if status.not_trading() > 0 || c.statuses {
+        println!();
+        for (s, duration) in &status.durations {
+            if *duration == 0 && !c.statuses {
                 continue;
             }
-
-            if c.debug {
-                println!("{}: p: {}, c: {}", data.pub_slot, data.price, data.conf);
+            let pct = Decimal::percent(*duration, status.total());
+            println!("{:.<20} {}{} ({}%)", s.name(), duration, unit, pct);
+        }
+    }
+    if c.statuses {
+        println!("Status transitions: {}", status.transitions.len());
+        for t in &status.transitions {
+            println!(
+                "{:>12} {:<26} {} -> {} ({})",
+                t.pub_slot,
+                Utc.timestamp_opt(t.block_time, 0).unwrap().to_rfc3339(),
+                t.from.name(),
+                t.to.name(),
+                t.publisher
+            );
+        }
+    }
+    if c.reconstruct {
+        match &report.aggregate {
+            Some(agg) => {
+                let r = &agg.reconstructed;
+                println!(
+                    "Reconstructed Aggregate: ${} +/- {} (slot {}, {} publisher(s))",
+                    r.price, r.conf, r.slot, r.num_publishers
+                );
+                println!(
+                    "Pyth Aggregate: ${} +/- {} (slot {})",
+                    agg.onchain_price, agg.onchain_conf, agg.onchain_slot
+                );
+                if !agg.matched_slot {
+                    println!("Pyth aggregate slot is outside the window, comparing the latest reconstruction");
+                }
+                if let (Some(price), Some(conf)) = (agg.price_deviation(), agg.conf_deviation()) {
+                    println!("Aggregate Deviation: ${} +/- {}", price, conf);
+                }
             }
-            if low == None || data.price < low.unwrap() {
-                low = Some(data.price);
+            None => println!("Not enough publisher updates to reconstruct the aggregate"),
+        }
+    }
+    if c.publishers {
+        println!();
+        println!(
+            "{:<44} {:>8} {:>20} {:>16} {:>16}",
+            "publisher", "updates", "twap", "mean deviation", "mean conf"
+        );
+        for p in &report.publishers {
+            let deviation = match &p.mean_deviation {
+                Some(d) => d.to_string(),
+                None => "-".to_string(),
+            };
+            println!(
+                "{:<44} {:>8} {:>20} {:>16} {:>16}",
+                p.publisher.to_string(),
+                p.count,
+                p.twap,
+                deviation,
+                p.mean_conf
+            );
+        }
+    }
+    if c.candle_size.is_some() {
+        let candles = candle::render(&report.candles, &report.window, c.format);
+        match &c.output {
+            Some(path) => {
+                if let Err(error) = fs::write(path, candles) {
+                    println!("Output Err: {}", error);
+                    process::exit(1);
+                }
+                println!("Wrote {} candle(s) to {}", report.candles.len(), path);
             }
-            if high == None || data.price > high.unwrap() {
-                high = Some(data.price);
+            None => {
+                println!();
+                print!("{}", candles);
             }
-            if open_slot == None || data.pub_slot < open_slot.unwrap() {
-                open_slot = Some(data.pub_slot);
-                open = Some(data.price);
+        }
+    }
+    if let Some(max) = &c.max_deviation {
+        match &report.onchain {
+            Some(onchain) if onchain.exceeds(max) => {
+                println!("Deviation from the on-chain TWAP is more than {}%", max);
+                process::exit(2);
             }
-            if close_slot == None || data.pub_slot > close_slot.unwrap() {
-                close_slot = Some(data.pub_slot);
-                close = Some(data.price);
+            Some(_) => {}
+            None => {
+                println!(
+                    "Pyth Err: {:?}",
+                    "cannot check the deviation, the on-chain TWAP is not the one at the window end"
+                );
+                process::exit(1);
             }
-
-            // update progress bar
-            let progress_microseconds = (start_t - block_t).num_microseconds().unwrap();
-            let time_progress =
-                (100.0 * progress_microseconds as f32) / (interval_microseconds as f32);
-            progress_bar.set_progression(time_progress as usize);
         }
-        if c.debug {
-            println!("getting next batch of transactions");
-        }
-    }
-
-    // on a small enough interval there may not be enough data especially with pyth in beta
-    if open == None
-        || close == None
-        || high == None
-        || low == None
-        || open_slot == None
-        || close_slot == None
-    {
-        println!("Error calculating TWAP - not enough data");
     }
-
-    let base: f32 = 10.0;
-    let scale_factor: f32 = base.powi(price_account.expo);
-    let open_price = (open.unwrap() as f32) * scale_factor;
-    let close_price = (close.unwrap() as f32) * scale_factor;
-    let low_price = (low.unwrap() as f32) * scale_factor;
-    let high_price = (high.unwrap() as f32) * scale_factor;
-    let twap_price = (open_price + close_price + low_price + high_price) / 4.0;
-
-    println!("");
-    println!("TWAP Interval: {} minute(s)", c.interval.num_minutes());
-    println!("Open: ${} ({})", open_price, open_slot.unwrap());
-    println!("High: ${}", high_price);
-    println!("Low: ${}", low_price);
-    println!("Close: ${} ({})", close_price, close_slot.unwrap());
-    println!("Calculated TWAP Price: ${}", twap_price);
-    // let pyth_twap_price = (price_account.twap as f32) * scale_factor;
-    // println!("Pyth TWAP Price: ${}", pyth_twap_price);
 }
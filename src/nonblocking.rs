@@ -0,0 +1,289 @@
+use crate::fetch::decode_updates;
+use crate::index::{ProductIndex, MAX_MULTIPLE_ACCOUNTS};
+use crate::pyth::{parse_mapping, parse_price, PriceAccount, PriceLookup, ProductResult};
+use crate::retry::{Failure, RateLimiter, RetryPolicy};
+use crate::twap::PriceUpdate;
+use crate::window::{Position, Window};
+use futures::stream::{self, StreamExt};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use solana_account_decoder::UiAccount;
+use solana_client::rpc_response::{Response, RpcConfirmedTransactionStatusWithSignature};
+use solana_program::pubkey::Pubkey;
+use solana_sdk::account::Account;
+use solana_transaction_status::EncodedConfirmedTransaction;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+// the rpc node returns up to 1000 signatures per page
+const SIGNATURE_PAGE_SIZE: usize = 1000;
+
+// PythClient for async code, the rpc calls neither block the executor nor need blocking threads
+// solana-client only has a nonblocking RpcClient from 1.10 on, so the few json-rpc
+// calls needed are made directly and decoded with the solana types
+pub struct AsyncPythClient {
+    http: reqwest::Client,
+    url: String,
+    id: AtomicU64,
+    pub retry: RetryPolicy,
+    limiter: RateLimiter,
+}
+
+impl AsyncPythClient {
+    pub fn new(url: &str) -> Result<AsyncPythClient, &'static str> {
+        AsyncPythClient::with_retry(url, RetryPolicy::default())
+    }
+    pub fn with_retry(url: &str, retry: RetryPolicy) -> Result<AsyncPythClient, &'static str> {
+        if reqwest::Url::parse(url).is_err() {
+            return Err("not a valid rpc url");
+        }
+        Ok(AsyncPythClient {
+            http: reqwest::Client::new(),
+            url: url.to_string(),
+            id: AtomicU64::new(1),
+            limiter: RateLimiter::new(retry.requests_per_second),
+            retry,
+        })
+    }
+    // make a json-rpc call under the rate limit, retrying it with backoff while the failure may pass
+    async fn request<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: Value,
+    ) -> Result<T, &'static str> {
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": self.id.fetch_add(1, Ordering::Relaxed),
+            "method": method,
+            "params": params,
+        });
+        // the same limiter, backoff and give up rule as PythClient::call
+        let mut attempts = 0;
+        let result = loop {
+            tokio::time::sleep(self.limiter.reserve()).await;
+            let (failure, error) = match self.attempt(&body).await {
+                Ok(result) => break result,
+                Err(failure) => failure,
+            };
+            attempts += 1;
+            match self.retry.after(attempts, failure) {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => return Err(error),
+            }
+        };
+        match serde_json::from_value(result) {
+            Ok(result) => Ok(result),
+            Err(_) => Err("unexpected rpc response"),
+        }
+    }
+    // one post of the request, failures are classified the way PythClient classifies them
+    async fn attempt(&self, body: &Value) -> Result<Value, (Failure, &'static str)> {
+        let response = match self.http.post(&self.url).json(body).send().await {
+            Ok(response) => response,
+            Err(_) => return Err((Failure::Transient, "rpc request failed")),
+        };
+        let status = response.status();
+        if !status.is_success() {
+            return Err((Failure::from_status(status), "rpc request failed"));
+        }
+        let mut reply: Value = match response.json().await {
+            Ok(reply) => reply,
+            Err(_) => return Err((Failure::Transient, "rpc response is not json")),
+        };
+        if let Some(error) = reply.get("error") {
+            let failure = match error["code"].as_i64() {
+                Some(code) => Failure::from_rpc_code(code),
+                None => Failure::Permanent,
+            };
+            return Err((failure, "rpc node returned an error"));
+        }
+        Ok(reply["result"].take())
+    }
+    pub async fn get_account(&self, key: &Pubkey) -> Result<Account, &'static str> {
+        let params = json!([key.to_string(), { "encoding": "base64" }]);
+        let response: Response<Option<UiAccount>> = self.request("getAccountInfo", params).await?;
+        match response.value.and_then(|a| a.decode()) {
+            Some(account) => Ok(account),
+            None => Err("account not found"),
+        }
+    }
+    // one page of signatures newest first, starting before the given signature
+    pub async fn get_signatures(
+        &self,
+        key: &Pubkey,
+        before: Option<&str>,
+    ) -> Result<Vec<RpcConfirmedTransactionStatusWithSignature>, &'static str> {
+        let params = json!([key.to_string(), { "before": before, "limit": SIGNATURE_PAGE_SIZE }]);
+        self.request("getSignaturesForAddress", params).await
+    }
+    pub async fn get_transaction(
+        &self,
+        signature: &str,
+    ) -> Result<EncodedConfirmedTransaction, &'static str> {
+        let params = json!([signature, "base64"]);
+        let txn: Option<EncodedConfirmedTransaction> =
+            self.request("getTransaction", params).await?;
+        match txn {
+            Some(txn) => Ok(txn),
+            None => Err("error getting price account transaction"),
+        }
+    }
+    pub async fn get_multiple_accounts(
+        &self,
+        keys: &[Pubkey],
+    ) -> Result<Vec<Option<Account>>, &'static str> {
+        let keys: Vec<String> = keys.iter().map(|k| k.to_string()).collect();
+        let params = json!([keys, { "encoding": "base64" }]);
+        let response: Response<Vec<Option<UiAccount>>> =
+            self.request("getMultipleAccounts", params).await?;
+        Ok(response
+            .value
+            .into_iter()
+            .map(|a| a.and_then(|a| a.decode()))
+            .collect())
+    }
+    pub async fn get_product_account(
+        &self,
+        map_key: &str,
+        symbol: &str,
+    ) -> Result<ProductResult, &'static str> {
+        self.load_index(map_key).await?.get(symbol)
+    }
+    // read every mapping account, then their product accounts in batches
+    pub async fn load_index(&self, map_key: &str) -> Result<ProductIndex, &'static str> {
+        let mut akey = match Pubkey::from_str(map_key) {
+            Ok(key) => key,
+            Err(_) => return Err("not a valid pyth mapping account"),
+        };
+        let mut product_keys = Vec::new();
+        loop {
+            let map_acct = match self.get_account(&akey).await {
+                Ok(account) => account,
+                Err(_) => return Err("not a valid pyth mapping account"),
+            };
+            let (products, next) = parse_mapping(&map_acct.data)?;
+            product_keys.extend(products);
+            akey = match next {
+                Some(next) => next,
+                None => break,
+            };
+        }
+
+        let mut index = ProductIndex::default();
+        for keys in product_keys.chunks(MAX_MULTIPLE_ACCOUNTS) {
+            let accounts = self.get_multiple_accounts(keys).await?;
+            for (key, account) in keys.iter().zip(accounts) {
+                index.insert(*key, account.as_ref().map(|a| a.data.as_slice()));
+            }
+        }
+        Ok(index)
+    }
+    pub async fn get_price_account(&self, px_acct: [u8; 32]) -> Result<PriceAccount, &'static str> {
+        let mut price_pkey = Pubkey::new(&px_acct);
+        loop {
+            let price_acct = match self.get_account(&price_pkey).await {
+                Ok(account) => account,
+                Err(_) => return Err("error getting price data"),
+            };
+            match parse_price(price_pkey, price_acct.owner, &price_acct.data)? {
+                PriceLookup::Found(price_account) => return Ok(price_account),
+                PriceLookup::Next(next) => price_pkey = next,
+            }
+        }
+    }
+    // every update in the window, newest transaction first
+    // transactions are fetched concurrency at a time but come out in signature order,
+    // the stream ends after the window is walked or with the first error
+    pub fn stream_updates(
+        self: &Arc<Self>,
+        price_account: &PriceAccount,
+        window: Window,
+        concurrency: usize,
+    ) -> mpsc::Receiver<Result<PriceUpdate, &'static str>> {
+        let (tx, rx) = mpsc::channel(SIGNATURE_PAGE_SIZE);
+        let client = Arc::clone(self);
+        let key = price_account.key;
+        let program_id = price_account.program_id;
+        tokio::spawn(async move {
+            let walked = client
+                .walk(&key, &program_id, window, concurrency.max(1), &tx)
+                .await;
+            if let Err(e) = walked {
+                let _ = tx.send(Err(e)).await;
+            }
+        });
+        rx
+    }
+    async fn walk(
+        &self,
+        key: &Pubkey,
+        program_id: &Pubkey,
+        window: Window,
+        concurrency: usize,
+        tx: &mpsc::Sender<Result<PriceUpdate, &'static str>>,
+    ) -> Result<(), &'static str> {
+        let mut before: Option<String> = None;
+        loop {
+            let page = self.get_signatures(key, before.as_deref()).await?;
+            // walked past the oldest signature of the account
+            let last = match page.last() {
+                Some(last) => last.signature.clone(),
+                None => return Ok(()),
+            };
+            before = Some(last);
+
+            let mut older = false;
+            let mut jobs = Vec::new();
+            for sig in page {
+                if sig.err.is_some() {
+                    continue;
+                }
+                let block_time = match sig.block_time {
+                    Some(t) => t,
+                    None => continue,
+                };
+                match window.locate(sig.slot, block_time) {
+                    Position::Newer => continue,
+                    Position::Older => {
+                        older = true;
+                        break;
+                    }
+                    Position::Inside => jobs.push((sig.signature, sig.slot, block_time)),
+                }
+            }
+
+            let mut fetched = stream::iter(jobs)
+                .map(|(signature, slot, block_time)| async move {
+                    let txn = self.get_transaction(&signature).await?;
+                    Ok::<_, &'static str>(decode_updates(&txn, slot, block_time, program_id, key))
+                })
+                .buffered(concurrency);
+            while let Some(updates) = fetched.next().await {
+                for u in updates? {
+                    if !window.contains_update(u.pub_slot) {
+                        continue;
+                    }
+                    // nobody is listening anymore
+                    if tx.send(Ok(u)).await.is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+            if older {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::nonblocking::AsyncPythClient;
+
+    #[test]
+    fn checks_url() {
+        assert!(AsyncPythClient::new("api.devnet.solana.com").is_err());
+    }
+}
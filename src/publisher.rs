@@ -0,0 +1,138 @@
+use crate::aggregate;
+use crate::decimal::Decimal;
+use crate::twap::TwapAccumulator;
+use crate::window::WindowEnd;
+use solana_program::pubkey::Pubkey;
+use std::collections::HashMap;
+
+pub struct PublisherStats {
+    pub publisher: Pubkey,
+    pub count: usize,
+    // the publisher's own TWAP over the window
+    pub twap: Decimal,
+    // mean absolute distance of the publisher's updates from the aggregate in force when they
+    // were sent, None when every update was sent before the first aggregate of the window
+    pub mean_deviation: Option<Decimal>,
+    pub mean_conf: Decimal,
+}
+
+// per publisher update counts, TWAP, deviation and conf over acc, busiest publisher first
+// an update is compared against the latest aggregate rebuilt from the updates seen before its
+// slot, seen holds every update the way the program does, whatever was filtered out of acc
+pub fn breakdown(
+    acc: &TwapAccumulator,
+    seen: &TwapAccumulator,
+    min_publishers: usize,
+    end: WindowEnd,
+) -> Vec<PublisherStats> {
+    let expo = acc.expo();
+    let aggregates = aggregate::reconstruct(seen, min_publishers);
+    // (updates, deviation sum, updates compared, conf sum)
+    let mut per_publisher: HashMap<Pubkey, (TwapAccumulator, i128, i128, i128)> = HashMap::new();
+
+    for u in acc.updates() {
+        let entry = per_publisher
+            .entry(u.publisher)
+            .or_insert_with(|| (TwapAccumulator::new(expo), 0, 0, 0));
+        entry.0.ingest(u.clone());
+        entry.3 += u.conf as i128;
+        // aggregates are ordered by slot
+        let in_force = aggregates.partition_point(|a| a.slot <= u.slot);
+        if let Some(a) = in_force.checked_sub(1).map(|i| &aggregates[i]) {
+            entry.1 += (u.price as i128 - a.price.mantissa).abs();
+            entry.2 += 1;
+        }
+    }
+
+    let mut stats: Vec<PublisherStats> = per_publisher
+        .into_iter()
+        .filter_map(
+            |(publisher, (updates, deviation_sum, compared, conf_sum))| {
+                let count = updates.count();
+                // None when nothing was compared
+                let mean_deviation = Decimal::new(deviation_sum, expo).div_int(compared, expo);
+                Some(PublisherStats {
+                    publisher,
+                    count,
+                    twap: updates.twap(end)?,
+                    mean_deviation,
+                    mean_conf: Decimal::new(conf_sum, expo).div_int(count as i128, expo)?,
+                })
+            },
+        )
+        .collect();
+    stats.sort_by(|a, b| b.count.cmp(&a.count).then(a.publisher.cmp(&b.publisher)));
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decimal::Decimal;
+    use crate::publisher::breakdown;
+    use crate::pyth::Status;
+    use crate::twap::{PriceUpdate, TwapAccumulator};
+    use crate::window::WindowEnd;
+    use solana_program::pubkey::Pubkey;
+
+    #[test]
+    fn splits_by_publisher() {
+        let a = Pubkey::new(&[1; 32]);
+        let b = Pubkey::new(&[2; 32]);
+        let mut acc = TwapAccumulator::new(0);
+        for (slot, publisher, price, conf) in [
+            (1, a, 100, 2),
+            (2, b, 110, 4),
+            (3, a, 102, 2),
+            (4, a, 104, 2),
+        ] {
+            acc.ingest(PriceUpdate {
+                pub_slot: slot,
+                slot,
+                block_time: slot as i64 * 10,
+                price,
+                conf,
+                status: Status::Trading,
+                publisher,
+            });
+        }
+        let stats = breakdown(&acc, &acc, 1, WindowEnd::Time(50));
+        assert_eq!(stats.len(), 2);
+        assert_eq!((stats[0].publisher, stats[0].count), (a, 3));
+        // 100 for 20s, 102 for 10s, 104 for 10s
+        assert_eq!(stats[0].twap, Decimal::new(1015, -1).rescale(0).unwrap());
+        assert_eq!(stats[0].mean_conf, Decimal::new(2, 0));
+        // the aggregate of slot 2 is a's 100, of slot 3 the median vote of 100 +- 2 and 110 +- 4
+        // which is 104, of slot 4 the median vote of 102 +- 2 and 110 +- 4 which is 105
+        // b: 110 vs 100
+        assert_eq!(stats[1].mean_deviation, Some(Decimal::new(10, 0)));
+        // a: nothing to compare the first update against, then 102 vs 104 and 104 vs 105
+        assert_eq!(stats[0].mean_deviation, Decimal::new(15, -1).rescale(0));
+    }
+    #[test]
+    fn compares_against_every_update_seen() {
+        let a = Pubkey::new(&[1; 32]);
+        let b = Pubkey::new(&[2; 32]);
+        let update = |slot: u64, publisher: Pubkey, price: i64| PriceUpdate {
+            pub_slot: slot,
+            slot,
+            block_time: slot as i64 * 10,
+            price,
+            conf: 1,
+            status: Status::Trading,
+            publisher,
+        };
+        let mut seen = TwapAccumulator::new(0);
+        let mut accepted = TwapAccumulator::new(0);
+        for u in [update(1, a, 100), update(1, b, 300), update(2, a, 100)] {
+            // b's print was filtered out of the TWAP but pyth aggregated it all the same
+            if u.publisher == a {
+                accepted.ingest(u.clone());
+            }
+            seen.ingest(u);
+        }
+        let stats = breakdown(&accepted, &seen, 1, WindowEnd::Time(30));
+        assert_eq!(stats.len(), 1);
+        // a's 100 at slot 2 against the aggregate of 100 and 300, not against its own 100
+        assert_eq!(stats[0].mean_deviation, Some(Decimal::new(100, 0)));
+    }
+}
@@ -1,22 +1,258 @@
+use crate::index::{ProductIndex, MAX_MULTIPLE_ACCOUNTS};
+use crate::retry::{Failure, RateLimiter, RetryPolicy};
 use pyth_client::{
     AccountType, Mapping, Price, PriceStatus, PriceType, Product, MAGIC, PROD_HDR_SIZE, VERSION_2,
 };
+use solana_client::client_error::{ClientError, ClientErrorKind, Result as ClientResult};
 use solana_client::rpc_client::RpcClient;
+use solana_client::rpc_request::RpcError;
 use solana_program::pubkey::Pubkey;
+use solana_sdk::message::Message;
+use solana_sdk::signature::Signature;
+use solana_transaction_status::UiTransactionEncoding;
 use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
 use std::str::FromStr;
+use std::thread;
 
-#[repr(C)]
+pub const DEVNET_PROGRAM_ID: &str = "gSbePebfvPy7tRqimPoVecS2UsBvYv46ynrzWocc92s";
+
+// every instruction starts with the program version and the command
+const CMD_HDR_SIZE: usize = 8;
+
+// price status as sent by publishers, mirrors pyth_client::PriceStatus
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Status {
+    Unknown,
+    Trading,
+    Halted,
+    Auction,
+}
+
+impl Status {
+    pub fn from_u32(status: u32) -> Option<Status> {
+        match status {
+            0 => Some(Status::Unknown),
+            1 => Some(Status::Trading),
+            2 => Some(Status::Halted),
+            3 => Some(Status::Auction),
+            _ => None,
+        }
+    }
+    pub fn parse(name: &str) -> Option<Status> {
+        match name {
+            "unknown" => Some(Status::Unknown),
+            "trading" => Some(Status::Trading),
+            "halted" => Some(Status::Halted),
+            "auction" => Some(Status::Auction),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Status::Unknown => "unknown",
+            Status::Trading => "trading",
+            Status::Halted => "halted",
+            Status::Auction => "auction",
+        }
+    }
+}
+
+// payload of upd_price and upd_price_no_fail_on_error
+#[derive(Clone, Debug, PartialEq)]
 pub struct UpdatePriceInstruction {
-    pub version: u32,
-    pub cmd: i32,
-    pub status: PriceStatus,
-    pub unused: u32,
+    pub status: Status,
     pub price: i64,
     pub conf: u64,
     pub pub_slot: u64,
 }
 
+// every command of the pyth v2 oracle program
+#[derive(Clone, Debug, PartialEq)]
+pub enum PythInstruction {
+    InitMapping,
+    AddMapping,
+    AddProduct,
+    UpdProduct { attributes: Vec<u8> },
+    AddPrice { expo: i32, ptype: u32 },
+    AddPublisher { publisher: Pubkey },
+    DelPublisher { publisher: Pubkey },
+    UpdPrice(UpdatePriceInstruction),
+    AggPrice,
+    InitPrice { expo: i32, ptype: u32 },
+    InitTest,
+    UpdTest { data: Vec<u8> },
+    SetMinPub { min_pub: u8 },
+    UpdPriceNoFailOnError(UpdatePriceInstruction),
+}
+
+fn read_u32(d: &[u8], offset: usize) -> Result<u32, &'static str> {
+    match d.get(offset..offset + 4) {
+        Some(b) => Ok(u32::from_le_bytes(b.try_into().unwrap())),
+        None => Err("instruction data too short"),
+    }
+}
+fn read_u64(d: &[u8], offset: usize) -> Result<u64, &'static str> {
+    match d.get(offset..offset + 8) {
+        Some(b) => Ok(u64::from_le_bytes(b.try_into().unwrap())),
+        None => Err("instruction data too short"),
+    }
+}
+fn read_key(d: &[u8], offset: usize) -> Result<Pubkey, &'static str> {
+    match d.get(offset..offset + 32) {
+        Some(b) => Ok(Pubkey::new(b)),
+        None => Err("instruction data too short"),
+    }
+}
+
+impl PythInstruction {
+    pub fn decode(d: &[u8]) -> Result<PythInstruction, &'static str> {
+        if read_u32(d, 0)? != VERSION_2 {
+            return Err("unsupported pyth program version");
+        }
+        let cmd = read_u32(d, 4)? as i32;
+        let body = &d[CMD_HDR_SIZE..];
+        let instruction = match cmd {
+            0 => PythInstruction::InitMapping,
+            1 => PythInstruction::AddMapping,
+            2 => PythInstruction::AddProduct,
+            3 => PythInstruction::UpdProduct {
+                attributes: body.to_vec(),
+            },
+            4 => PythInstruction::AddPrice {
+                expo: read_u32(body, 0)? as i32,
+                ptype: read_u32(body, 4)?,
+            },
+            5 => PythInstruction::AddPublisher {
+                publisher: read_key(body, 0)?,
+            },
+            6 => PythInstruction::DelPublisher {
+                publisher: read_key(body, 0)?,
+            },
+            7 => PythInstruction::UpdPrice(decode_update_price(body)?),
+            8 => PythInstruction::AggPrice,
+            9 => PythInstruction::InitPrice {
+                expo: read_u32(body, 0)? as i32,
+                ptype: read_u32(body, 4)?,
+            },
+            10 => PythInstruction::InitTest,
+            11 => PythInstruction::UpdTest {
+                data: body.to_vec(),
+            },
+            12 => match body.first() {
+                Some(min_pub) => PythInstruction::SetMinPub { min_pub: *min_pub },
+                None => return Err("instruction data too short"),
+            },
+            13 => PythInstruction::UpdPriceNoFailOnError(decode_update_price(body)?),
+            _ => return Err("unknown pyth command"),
+        };
+        Ok(instruction)
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            PythInstruction::InitMapping => "init_mapping",
+            PythInstruction::AddMapping => "add_mapping",
+            PythInstruction::AddProduct => "add_product",
+            PythInstruction::UpdProduct { .. } => "upd_product",
+            PythInstruction::AddPrice { .. } => "add_price",
+            PythInstruction::AddPublisher { .. } => "add_publisher",
+            PythInstruction::DelPublisher { .. } => "del_publisher",
+            PythInstruction::UpdPrice(_) => "upd_price",
+            PythInstruction::AggPrice => "agg_price",
+            PythInstruction::InitPrice { .. } => "init_price",
+            PythInstruction::InitTest => "init_test",
+            PythInstruction::UpdTest { .. } => "upd_test",
+            PythInstruction::SetMinPub { .. } => "set_min_pub",
+            PythInstruction::UpdPriceNoFailOnError(_) => "upd_price_no_fail_on_error",
+        }
+    }
+    // what each account passed to the instruction is for
+    pub fn account_roles(&self) -> &'static [&'static str] {
+        match self {
+            PythInstruction::InitMapping => &["funding", "mapping"],
+            PythInstruction::AddMapping => &["funding", "tail mapping", "new mapping"],
+            PythInstruction::AddProduct => &["funding", "mapping", "new product"],
+            PythInstruction::UpdProduct { .. } => &["funding", "product"],
+            PythInstruction::AddPrice { .. } => &["funding", "product", "new price"],
+            PythInstruction::AddPublisher { .. } | PythInstruction::DelPublisher { .. } => {
+                &["funding", "price"]
+            }
+            PythInstruction::UpdPrice(_)
+            | PythInstruction::AggPrice
+            | PythInstruction::UpdPriceNoFailOnError(_) => &["publisher", "price", "clock"],
+            PythInstruction::InitPrice { .. } => &["funding", "price"],
+            PythInstruction::InitTest | PythInstruction::UpdTest { .. } => &["funding", "test"],
+            PythInstruction::SetMinPub { .. } => &["funding", "price"],
+        }
+    }
+    // the new publisher price for every variant that carries one
+    pub fn price_update(&self) -> Option<&UpdatePriceInstruction> {
+        match self {
+            PythInstruction::UpdPrice(u) | PythInstruction::UpdPriceNoFailOnError(u) => Some(u),
+            _ => None,
+        }
+    }
+}
+
+// status u32, unused u32, price i64, conf u64, pub_slot u64
+fn decode_update_price(body: &[u8]) -> Result<UpdatePriceInstruction, &'static str> {
+    let status = match Status::from_u32(read_u32(body, 0)?) {
+        Some(status) => status,
+        None => return Err("unknown price status"),
+    };
+    Ok(UpdatePriceInstruction {
+        status,
+        price: read_u64(body, 8)? as i64,
+        conf: read_u64(body, 16)?,
+        pub_slot: read_u64(body, 24)?,
+    })
+}
+
+impl fmt::Display for PythInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PythInstruction::UpdProduct { attributes } => {
+                write!(
+                    f,
+                    "{} ({} bytes of attributes)",
+                    self.name(),
+                    attributes.len()
+                )
+            }
+            PythInstruction::AddPrice { expo, ptype }
+            | PythInstruction::InitPrice { expo, ptype } => {
+                write!(f, "{} expo {} type {}", self.name(), expo, ptype)
+            }
+            PythInstruction::AddPublisher { publisher }
+            | PythInstruction::DelPublisher { publisher } => {
+                write!(f, "{} {}", self.name(), publisher)
+            }
+            PythInstruction::UpdPrice(u) | PythInstruction::UpdPriceNoFailOnError(u) => write!(
+                f,
+                "{} status {} price {} conf {} pub_slot {}",
+                self.name(),
+                u.status.name(),
+                u.price,
+                u.conf,
+                u.pub_slot
+            ),
+            PythInstruction::UpdTest { data } => {
+                write!(f, "{} ({} bytes)", self.name(), data.len())
+            }
+            PythInstruction::SetMinPub { min_pub } => write!(f, "{} {}", self.name(), min_pub),
+            _ => write!(f, "{}", self.name()),
+        }
+    }
+}
+
+// an instruction of a transaction sent to the pyth program, with the accounts it was given
+pub struct DecodedInstruction {
+    pub index: usize,
+    pub accounts: Vec<Pubkey>,
+    pub instruction: Result<PythInstruction, &'static str>,
+}
+
 pub struct ProductResult {
     pub key: Pubkey,
     pub price_accounts: [u8; 32],
@@ -24,20 +260,26 @@ pub struct ProductResult {
 
 pub struct PriceAccount {
     pub key: Pubkey,
+    // the pyth program that owns the account
+    pub program_id: Pubkey,
     pub expo: i32,
     pub twap: i64,
+    pub valid_slot: u64,
+    // latest aggregate of the publisher components
+    pub agg_price: i64,
+    pub agg_conf: u64,
+    pub agg_pub_slot: u64,
+    // unknown when too few publishers were trading for an aggregate
+    pub agg_status: Status,
 }
 
 pub trait PythAccount {
     fn is_valid(&self) -> bool;
     // cast byte string into structs
+    #[allow(clippy::new_ret_no_self)]
     fn new<T>(d: &[u8]) -> Option<&T> {
         let (_, pxa, _) = unsafe { d.align_to::<T>() };
-        if pxa.len() > 0 {
-            return Some(&pxa[0]);
-        } else {
-            return None;
-        }
+        pxa.first()
     }
 }
 impl PythAccount for Mapping {
@@ -58,31 +300,26 @@ impl PythAccount for Product {
         true
     }
 }
-trait PythProduct {
+pub trait PythProduct {
     fn get_symbol(&self) -> Option<String>;
     fn decode_attributes(&self) -> Option<HashMap<String, String>>;
 }
 
 impl PythProduct for Product {
     fn get_symbol(&self) -> Option<String> {
-        let attr_map = match self.decode_attributes() {
-            None => return None,
-            Some(i) => i,
-        };
+        let attr_map = self.decode_attributes()?;
         let k = String::from("symbol");
-        match attr_map.get(&k) {
-            Some(i) => return Some(i.clone()),
-            None => return None,
-        };
+        attr_map.get(&k).cloned()
     }
+    // None when the size or an attribute length runs past the account
     fn decode_attributes(&self) -> Option<HashMap<String, String>> {
         let mut attributes = HashMap::new();
-        let mut pr_attr_sz = self.size as usize - PROD_HDR_SIZE;
-        let mut pr_attr_it = (&self.attr[..]).iter();
+        let mut pr_attr_sz = (self.size as usize).checked_sub(PROD_HDR_SIZE)?;
+        let mut pr_attr_it = self.attr[..].iter();
         while pr_attr_sz > 0 {
-            let key = get_attr_str(&mut pr_attr_it);
-            let val = get_attr_str(&mut pr_attr_it);
-            pr_attr_sz -= 2 + key.len() + val.len();
+            let key = get_attr_str(&mut pr_attr_it)?;
+            let val = get_attr_str(&mut pr_attr_it)?;
+            pr_attr_sz = pr_attr_sz.checked_sub(2 + key.len() + val.len())?;
             // println!("{:.<16} {}", key, val);
             attributes.insert(key, val);
         }
@@ -102,137 +339,278 @@ impl PythAccount for Price {
         true
     }
 }
-impl PythAccount for UpdatePriceInstruction {
-    fn is_valid(&self) -> bool {
-        let _ = match &self.status {
-            PriceStatus::Trading => "trading",
-            _ => return false,
-        };
-        if self.price == 0 {
-            return false;
-        }
-        true
-    }
-}
 
 pub struct PythClient {
     pub client: RpcClient,
+    pub retry: RetryPolicy,
+    limiter: RateLimiter,
 }
 impl PythClient {
-    pub fn new(url: &String) -> Result<PythClient, &'static str> {
+    pub fn new(url: &str) -> Result<PythClient, &'static str> {
+        PythClient::with_retry(url, RetryPolicy::default())
+    }
+    pub fn with_retry(url: &str, retry: RetryPolicy) -> Result<PythClient, &'static str> {
         // url error handling
-        return Ok(PythClient {
+        Ok(PythClient {
             client: RpcClient::new(url.to_string()),
-        });
+            limiter: RateLimiter::new(retry.requests_per_second),
+            retry,
+        })
+    }
+    // make an rpc call under the rate limit, retrying it with backoff while the failure may pass
+    // the last error is returned once the attempts run out
+    pub fn call<T, F>(&self, f: F) -> ClientResult<T>
+    where
+        F: Fn(&RpcClient) -> ClientResult<T>,
+    {
+        let mut attempts = 0;
+        loop {
+            thread::sleep(self.limiter.reserve());
+            let error = match f(&self.client) {
+                Ok(result) => return Ok(result),
+                Err(error) => error,
+            };
+            attempts += 1;
+            match self.retry.after(attempts, classify(&error)) {
+                Some(delay) => thread::sleep(delay),
+                None => return Err(error),
+            }
+        }
     }
     pub fn get_product_account(
         &self,
         map_key: &str,
         symbol: &str,
     ) -> Result<ProductResult, &'static str> {
-        // read pyth_map_key account data and verify it is the correct account
+        let product = self.load_index(map_key)?.get(symbol);
+        if product.is_err() {
+            println!("See https://pyth.network/markets/ for a list of symbols");
+        }
+        product
+    }
+    // read every mapping account, then their product accounts in batches
+    pub fn load_index(&self, map_key: &str) -> Result<ProductIndex, &'static str> {
         // mapping accounts stored as linked list so we iterate until empty
-        let mut akey = Pubkey::from_str(&map_key).unwrap();
-
+        let mut akey = match Pubkey::from_str(map_key) {
+            Ok(key) => key,
+            Err(_) => return Err("not a valid pyth mapping account"),
+        };
+        let mut product_keys = Vec::new();
         loop {
-            let map_data = match self.client.get_account_data(&akey) {
+            let map_data = match self.call(|c| c.get_account_data(&akey)) {
                 Err(_) => return Err("not a valid pyth mapping account"),
                 Ok(i) => i,
             };
-            let map_acct = Mapping::new::<Mapping>(&map_data).unwrap();
-            if !map_acct.is_valid() {
-                return Err("not a valid pyth mapping account");
-            }
-
-            // loop over products until we find one that matches are symbol
-            let mut i = 0;
-            for prod_akey in &map_acct.products {
-                let prod_pkey = Pubkey::new(&prod_akey.val);
-                let prod_data = self.client.get_account_data(&prod_pkey).unwrap();
-                let prod_acct = match Product::new::<Product>(&prod_data) {
-                    Some(prod_acct) => prod_acct,
-                    None => continue, // go to next loop if no product account
-                };
-                if !prod_acct.is_valid() {
-                    continue;
-                }
-
-                // loop through reference attributes and find symbol
-                let prod_attr_sym = match prod_acct.get_symbol() {
-                    Some(s) => s,
-                    None => continue,
-                };
-                if prod_attr_sym != symbol {
-                    i += 1;
-                    if i == map_acct.num {
-                        break;
-                    }
-                    continue;
-                }
-                if !prod_acct.px_acc.is_valid() {
-                    return Err("pyth price account in valid");
-                }
-                return Ok(ProductResult {
-                    key: prod_pkey,
-                    price_accounts: prod_acct.px_acc.val,
-                });
-            }
+            let (products, next) = parse_mapping(&map_data)?;
+            product_keys.extend(products);
             // go to next Mapping account in list
-            if !map_acct.next.is_valid() {
-                break;
+            akey = match next {
+                Some(next) => next,
+                None => break,
+            };
+        }
+
+        let mut index = ProductIndex::default();
+        for keys in product_keys.chunks(MAX_MULTIPLE_ACCOUNTS) {
+            let accounts = match self.call(|c| c.get_multiple_accounts(keys)) {
+                Ok(accounts) => accounts,
+                Err(_) => return Err("error getting product accounts"),
+            };
+            for (key, account) in keys.iter().zip(accounts) {
+                index.insert(*key, account.as_ref().map(|a| a.data.as_slice()));
             }
-            akey = Pubkey::new(&map_acct.next.val);
         }
-        println!(
-            "See {} for a list of symbols",
-            "https://pyth.network/markets/"
-        );
-        return Err("product account not found");
+        Ok(index)
+    }
+    // the slot and decoded pyth instructions of a transaction
+    pub fn inspect_transaction(
+        &self,
+        signature: &str,
+        program_id: &str,
+    ) -> Result<(u64, Vec<DecodedInstruction>), &'static str> {
+        let signature = match Signature::from_str(signature) {
+            Ok(s) => s,
+            Err(_) => return Err("not a valid transaction signature"),
+        };
+        let program_id = match Pubkey::from_str(program_id) {
+            Ok(p) => p,
+            Err(_) => return Err("not a valid pyth program id"),
+        };
+        let txn = match self.call(|c| c.get_transaction(&signature, UiTransactionEncoding::Base64))
+        {
+            Ok(txn) => txn,
+            Err(_) => return Err("error getting transaction"),
+        };
+        let t = match txn.transaction.transaction.decode() {
+            Some(t) => t,
+            None => return Err("error decoding transaction"),
+        };
+        Ok((txn.slot, pyth_instructions(&t.message, &program_id)))
     }
     pub fn get_price_account(&self, px_acct: [u8; 32]) -> Result<PriceAccount, &'static str> {
         // check if price account is valid
         let mut price_pkey = Pubkey::new(&px_acct);
-        let mut p: &Price;
         loop {
-            let price_data = match self.client.get_account_data(&price_pkey) {
+            let price_acct = match self.call(|c| c.get_account(&price_pkey)) {
                 Ok(price_acct) => price_acct,
-                Err(_) => return Err("error getting price data"), // go to next loop if no product account
+                Err(_) => return Err("error getting price data"),
             };
-            p = Price::new::<Price>(&price_data).unwrap();
-            if p.is_valid() {
-                return Ok(PriceAccount {
-                    key: price_pkey,
-                    expo: p.expo,
-                    twap: p.twap,
-                });
+            match parse_price(price_pkey, price_acct.owner, &price_acct.data)? {
+                PriceLookup::Found(price_account) => return Ok(price_account),
+                PriceLookup::Next(next) => price_pkey = next,
             }
-            // go to next Mapping account in list
-            if !p.next.is_valid() {
-                return Err("price account not found");
-            }
-            price_pkey = Pubkey::new(&p.next.val);
+        }
+    }
+}
+
+// whether an rpc error may pass if the call is made again
+pub fn classify(error: &ClientError) -> Failure {
+    match error.kind() {
+        ClientErrorKind::Io(_) => Failure::Transient,
+        ClientErrorKind::Reqwest(e) => match e.status() {
+            Some(status) => Failure::from_status(status),
+            None => Failure::Transient,
+        },
+        ClientErrorKind::RpcError(RpcError::RpcRequestError(_)) => Failure::Transient,
+        ClientErrorKind::RpcError(RpcError::RpcResponseError { code, .. }) => {
+            Failure::from_rpc_code(*code)
+        }
+        _ => Failure::Permanent,
+    }
+}
+
+// the product accounts a valid mapping account lists and the next mapping account
+pub fn parse_mapping(data: &[u8]) -> Result<(Vec<Pubkey>, Option<Pubkey>), &'static str> {
+    let map_acct = match Mapping::new::<Mapping>(data) {
+        Some(map_acct) if map_acct.is_valid() => map_acct,
+        _ => return Err("not a valid pyth mapping account"),
+    };
+    let products = map_acct
+        .products
+        .iter()
+        .take(map_acct.num as usize)
+        .map(|p| Pubkey::new(&p.val))
+        .collect();
+    let next = if map_acct.next.is_valid() {
+        Some(Pubkey::new(&map_acct.next.val))
+    } else {
+        None
+    };
+    Ok((products, next))
+}
+
+// a product may list several price accounts, the first valid one is used
+pub enum PriceLookup {
+    Found(PriceAccount),
+    Next(Pubkey),
+}
+
+pub fn parse_price(key: Pubkey, owner: Pubkey, data: &[u8]) -> Result<PriceLookup, &'static str> {
+    let p = match Price::new::<Price>(data) {
+        Some(p) => p,
+        None => return Err("not a valid pyth price account"),
+    };
+    if p.is_valid() {
+        return Ok(PriceLookup::Found(PriceAccount {
+            key,
+            program_id: owner,
+            expo: p.expo,
+            twap: p.twap,
+            valid_slot: p.valid_slot,
+            agg_price: p.agg.price,
+            agg_conf: p.agg.conf,
+            agg_pub_slot: p.agg.pub_slot,
+            agg_status: match p.agg.status {
+                PriceStatus::Unknown => Status::Unknown,
+                PriceStatus::Trading => Status::Trading,
+                PriceStatus::Halted => Status::Halted,
+                PriceStatus::Auction => Status::Auction,
+            },
+        }));
+    }
+    // go to next price account in list
+    if !p.next.is_valid() {
+        return Err("price account not found");
+    }
+    Ok(PriceLookup::Next(Pubkey::new(&p.next.val)))
+}
+
+// every instruction in a transaction sent to the pyth program
+pub fn pyth_instructions(message: &Message, program_id: &Pubkey) -> Vec<DecodedInstruction> {
+    let mut decoded = Vec::new();
+    for (index, i) in message.instructions.iter().enumerate() {
+        if message.account_keys.get(i.program_id_index as usize) != Some(program_id) {
+            continue;
+        }
+        // a missing account would shift the roles of the ones after it, skip the instruction
+        let accounts: Option<Vec<Pubkey>> = i
+            .accounts
+            .iter()
+            .map(|a| message.account_keys.get(*a as usize).cloned())
+            .collect();
+        let accounts = match accounts {
+            Some(accounts) => accounts,
+            None => continue,
+        };
+        decoded.push(DecodedInstruction {
+            index,
+            accounts,
+            instruction: PythInstruction::decode(&i.data),
+        });
+    }
+    decoded
+}
+
+// every price update in a transaction addressed to the pyth program for the price account,
+// with the publisher that signed it (accounts are publisher, price account, clock)
+pub fn price_updates(
+    message: &Message,
+    program_id: &Pubkey,
+    price_key: &Pubkey,
+) -> Vec<(Pubkey, UpdatePriceInstruction)> {
+    let mut updates = Vec::new();
+    for decoded in pyth_instructions(message, program_id) {
+        if decoded.accounts.get(1) != Some(price_key) {
             continue;
         }
+        let publisher = match decoded.accounts.first() {
+            Some(publisher) => *publisher,
+            None => continue,
+        };
+        if let Ok(instruction) = &decoded.instruction {
+            if let Some(update) = instruction.price_update() {
+                updates.push((publisher, update.clone()));
+            }
+        }
     }
+    updates
 }
 
-pub fn get_attr_str<'a, T>(ite: &mut T) -> String
+// a length prefixed string, None when the bytes run out first
+pub fn get_attr_str<'a, T>(ite: &mut T) -> Option<String>
 where
     T: Iterator<Item = &'a u8>,
 {
-    let mut len = *ite.next().unwrap() as usize;
+    let mut len = *ite.next()? as usize;
     let mut val = String::with_capacity(len);
     while len > 0 {
-        val.push(*ite.next().unwrap() as char);
+        val.push(*ite.next()? as char);
         len -= 1;
     }
-    return val;
+    Some(val)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::pyth::{PythAccount, PythProduct};
+    use crate::pyth::{
+        classify, price_updates, pyth_instructions, PythAccount, PythInstruction, PythProduct,
+        Status,
+    };
+    use crate::retry::Failure;
     use pyth_client::{AccKey, AccountType, Product, MAGIC, VERSION_2};
+    use solana_program::pubkey::Pubkey;
+    use solana_sdk::instruction::CompiledInstruction;
+    use solana_sdk::message::Message;
 
     #[cfg(test)]
     struct Setup {
@@ -284,9 +662,9 @@ mod tests {
     #[test]
     fn invalid_product_acct() {
         let mut s = Setup::new();
-        assert_eq!(s.product.is_valid(), true);
-        s.product.magic = s.product.magic - 1;
-        assert_eq!(s.product.is_valid(), false);
+        assert!(s.product.is_valid());
+        s.product.magic -= 1;
+        assert!(!s.product.is_valid());
     }
     #[test]
     fn valid_product_symbol() {
@@ -294,4 +672,107 @@ mod tests {
         let expected_symbol = String::from("ETH/USD");
         assert_eq!(symbol, expected_symbol);
     }
+    fn upd_price_data(price: i64, pub_slot: u64) -> Vec<u8> {
+        let mut d = vec![2, 0, 0, 0, 7, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0];
+        d.extend_from_slice(&price.to_le_bytes());
+        d.extend_from_slice(&1u64.to_le_bytes());
+        d.extend_from_slice(&pub_slot.to_le_bytes());
+        d
+    }
+
+    fn instruction(program_id_index: u8, data: Vec<u8>, accounts: Vec<u8>) -> CompiledInstruction {
+        CompiledInstruction {
+            program_id_index,
+            accounts,
+            data,
+        }
+    }
+
+    // keys: 0 publisher, 1 our price account, 2 another price account, 3 clock,
+    // 4 pyth program, 5 another program
+    fn message(instructions: Vec<CompiledInstruction>) -> Message {
+        Message {
+            account_keys: (1..=6).map(|n| Pubkey::new(&[n; 32])).collect(),
+            instructions,
+            ..Message::default()
+        }
+    }
+
+    #[test]
+    fn filters_by_program_and_price_account() {
+        let program_id = Pubkey::new(&[5; 32]);
+        let price_key = Pubkey::new(&[2; 32]);
+        let message = message(vec![
+            instruction(4, upd_price_data(100, 1), vec![0, 1, 3]),
+            // the same update sent to another program
+            instruction(5, upd_price_data(200, 1), vec![0, 1, 3]),
+            // another price account in the same transaction
+            instruction(4, upd_price_data(300, 1), vec![0, 2, 3]),
+            instruction(4, upd_price_data(400, 2), vec![0, 1, 3]),
+        ]);
+        assert_eq!(pyth_instructions(&message, &program_id).len(), 3);
+        let updates = price_updates(&message, &program_id, &price_key);
+        let prices: Vec<i64> = updates.iter().map(|(_, u)| u.price).collect();
+        assert_eq!(prices, vec![100, 400]);
+        assert!(updates.iter().all(|(p, _)| *p == Pubkey::new(&[1; 32])));
+    }
+    #[test]
+    fn skips_instructions_with_missing_accounts() {
+        let program_id = Pubkey::new(&[5; 32]);
+        let price_key = Pubkey::new(&[2; 32]);
+        // without the out of range publisher the price account would look like the signer
+        let message = message(vec![
+            instruction(4, upd_price_data(100, 1), vec![9, 0, 1]),
+            instruction(4, upd_price_data(200, 1), vec![0, 1, 3]),
+        ]);
+        let decoded = pyth_instructions(&message, &program_id);
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded[0].index, 1);
+        let updates = price_updates(&message, &program_id, &price_key);
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].1.price, 200);
+    }
+    #[test]
+    fn decode_upd_price() {
+        let mut d = vec![2, 0, 0, 0, 7, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0];
+        d.extend_from_slice(&4_500_000_000i64.to_le_bytes());
+        d.extend_from_slice(&1_500_000u64.to_le_bytes());
+        d.extend_from_slice(&80_000_000u64.to_le_bytes());
+        let instruction = PythInstruction::decode(&d).unwrap();
+        assert_eq!(instruction.name(), "upd_price");
+        let update = instruction.price_update().unwrap();
+        assert_eq!(update.status, Status::Trading);
+        assert_eq!(update.price, 4_500_000_000);
+        assert_eq!(update.conf, 1_500_000);
+        assert_eq!(update.pub_slot, 80_000_000);
+
+        // same payload without fail on error
+        d[4] = 13;
+        let instruction = PythInstruction::decode(&d).unwrap();
+        assert_eq!(instruction.name(), "upd_price_no_fail_on_error");
+        assert!(instruction.price_update().is_some());
+    }
+    #[test]
+    fn decode_checks_length() {
+        let d = vec![2, 0, 0, 0, 7, 0, 0, 0, 1, 0, 0, 0];
+        assert!(PythInstruction::decode(&d).is_err());
+        let d = vec![2, 0, 0, 0, 5, 0, 0, 0, 1];
+        assert!(PythInstruction::decode(&d).is_err());
+        assert!(PythInstruction::decode(&[2, 0, 0]).is_err());
+        let d = vec![2, 0, 0, 0, 8, 0, 0, 0];
+        assert_eq!(PythInstruction::decode(&d), Ok(PythInstruction::AggPrice));
+        let d = vec![1, 0, 0, 0, 8, 0, 0, 0];
+        assert!(PythInstruction::decode(&d).is_err());
+    }
+    #[test]
+    fn classifies_rpc_errors() {
+        use solana_client::client_error::ClientError;
+        use solana_client::rpc_request::RpcError;
+        use std::io;
+
+        let io = ClientError::from(io::Error::new(io::ErrorKind::ConnectionReset, "reset"));
+        assert_eq!(classify(&io), Failure::Transient);
+        let missing = ClientError::from(RpcError::ForUser("AccountNotFound".to_string()));
+        assert_eq!(classify(&missing), Failure::Permanent);
+    }
 }
@@ -0,0 +1,411 @@
+use crate::aggregate::{self, AggregateComparison};
+use crate::cache::Cache;
+use crate::candle::{self, Candle};
+use crate::coverage::{self, Coverage};
+use crate::decimal::Decimal;
+use crate::fetch::DEFAULT_CONCURRENCY;
+use crate::filter::{OutlierFilter, Rejections};
+use crate::publisher::{self, PublisherStats};
+use crate::pyth::{PriceAccount, PythClient};
+use crate::retry::RetryPolicy;
+use crate::source::{Export, PriceUpdateSource, RpcSource};
+use crate::status::{self, StatusPolicy, StatusReport};
+use crate::twap::{Method, Ohlc, PriceUpdate, TwapAccumulator};
+use crate::window::Window;
+use solana_program::pubkey::Pubkey;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+pub const DEFAULT_MAPPING_KEY: &str = "BmA9Z6FjioHJPpjT39QazZyhDRUdZy2ezwx4GiDdE2u2";
+pub const DEVNET_URL: &str = "http://api.devnet.solana.com";
+// the on-chain TWAP stands for the window end when the price account last changed at most
+// this many slots (about a minute) after it, rpc nodes only serve the account as it is now
+pub const MAX_ONCHAIN_LAG: u64 = 150;
+
+pub struct TwapOptions {
+    pub url: String,
+    pub mapping_key: String,
+    pub method: Method,
+    // bucket the window into candles of this many seconds, or slots for a slot window
+    pub candle_size: Option<i64>,
+    // report gaps between accepted updates longer than this many seconds, or slots
+    pub max_gap: Option<i64>,
+    // refuse to calculate a TWAP when less of the window than this percent is covered
+    pub min_coverage: Option<Decimal>,
+    pub outliers: OutlierFilter,
+    // statuses besides trading whose updates count towards the TWAP
+    pub status_policy: StatusPolicy,
+    // replay the publisher updates through pyth's aggregation and compare with the on-chain aggregate
+    pub reconstruct: bool,
+    pub min_publishers: usize,
+    // break the window down by publisher
+    pub publishers: bool,
+    // number of transactions fetched at once
+    pub concurrency: usize,
+    // how failed rpc calls are retried and how fast calls are made
+    pub retry: RetryPolicy,
+    // keep fetched transactions in this directory and only fetch what it does not hold
+    pub cache_dir: Option<PathBuf>,
+    // write the updates of the window and the price account to this file to replay them later
+    pub export: Option<PathBuf>,
+    // print every decoded update and rpc error
+    pub debug: bool,
+    // draw a progress bar while walking the price account signatures
+    pub progress: bool,
+}
+
+impl Default for TwapOptions {
+    fn default() -> TwapOptions {
+        TwapOptions {
+            url: DEVNET_URL.to_string(),
+            mapping_key: DEFAULT_MAPPING_KEY.to_string(),
+            method: Method::Arithmetic,
+            candle_size: None,
+            max_gap: None,
+            min_coverage: None,
+            outliers: OutlierFilter::default(),
+            status_policy: StatusPolicy::default(),
+            reconstruct: false,
+            min_publishers: 1,
+            publishers: false,
+            concurrency: DEFAULT_CONCURRENCY,
+            retry: RetryPolicy::default(),
+            cache_dir: None,
+            export: None,
+            debug: false,
+            progress: false,
+        }
+    }
+}
+
+// the TWAP pyth keeps on-chain, read from the price account before the window was walked
+// pyth-client 0.2.0 price accounts have no twac, so only the TWAP is compared
+pub struct OnChainComparison {
+    pub twap: Decimal,
+    pub valid_slot: u64,
+    // the arithmetic TWAP of the window whatever the method, pyth averages the same way
+    pub calculated: Decimal,
+    // calculated minus on-chain, and the same relative to the on-chain TWAP in percent
+    pub deviation: Decimal,
+    pub deviation_pct: Option<Decimal>,
+}
+
+impl OnChainComparison {
+    // None when the deviation does not fit
+    pub fn new(
+        calculated: Decimal,
+        onchain: Decimal,
+        valid_slot: u64,
+    ) -> Option<OnChainComparison> {
+        let deviation = calculated.checked_sub(&onchain)?;
+        Some(OnChainComparison {
+            twap: onchain,
+            valid_slot,
+            calculated,
+            deviation,
+            deviation_pct: deviation.mul_int(100).and_then(|d| d.div(&onchain, -4)),
+        })
+    }
+    // whether the deviation is beyond max_pct percent either way
+    pub fn exceeds(&self, max_pct: &Decimal) -> bool {
+        match &self.deviation_pct {
+            Some(pct) => pct.abs() > *max_pct,
+            None => !self.deviation.is_zero(),
+        }
+    }
+}
+
+pub struct TwapReport {
+    pub symbol: String,
+    pub product_key: Pubkey,
+    pub price_key: Pubkey,
+    pub window: Window,
+    pub expo: i32,
+    pub ohlc: Ohlc,
+    pub method: Method,
+    pub twap: Decimal,
+    // time weighted average confidence and the TWAP of price -/+ conf
+    pub twac: Decimal,
+    pub twap_lower: Decimal,
+    pub twap_upper: Decimal,
+    // None when the price account changed too long after the window end to compare against
+    pub onchain: Option<OnChainComparison>,
+    pub candles: Vec<Candle>,
+    // empty unless asked for
+    pub publishers: Vec<PublisherStats>,
+    pub aggregate: Option<AggregateComparison>,
+    pub num_updates: usize,
+    // updates dropped by the outlier filter, by reason
+    pub rejections: Rejections,
+    // time in each status and status changes over every update, whatever the policy
+    pub status: StatusReport,
+    // gaps and coverage when a gap threshold was given
+    pub coverage: Option<Coverage>,
+}
+
+// look up the price account for symbol and calculate its TWAP over the window
+pub fn compute_twap(
+    symbol: &str,
+    window: Window,
+    options: &TwapOptions,
+) -> Result<TwapReport, &'static str> {
+    let pyth = Arc::new(PythClient::with_retry(&options.url, options.retry.clone())?);
+    let product_account = pyth.get_product_account(&options.mapping_key, symbol)?;
+    // read as close to the window end as the account can be, walking the window takes a while
+    let price_account = pyth.get_price_account(product_account.price_accounts)?;
+
+    let mut source = RpcSource::new(Arc::clone(&pyth));
+    source.concurrency = options.concurrency;
+    source.cache = options.cache_dir.as_ref().map(Cache::new);
+    source.debug = options.debug;
+    source.progress = options.progress;
+    let fetched = source.updates(&price_account, &window)?;
+    build_report(
+        symbol,
+        &product_account.key,
+        &price_account,
+        fetched,
+        window,
+        options,
+    )
+}
+
+// calculate the TWAP over the window from the updates of any source, the TWAP and
+// aggregate are compared against price_account as the on-chain state
+pub fn compute_twap_from<S: PriceUpdateSource + ?Sized>(
+    source: &mut S,
+    symbol: &str,
+    product_key: &Pubkey,
+    price_account: &PriceAccount,
+    window: Window,
+    options: &TwapOptions,
+) -> Result<TwapReport, &'static str> {
+    let fetched = source.updates(price_account, &window)?;
+    build_report(symbol, product_key, price_account, fetched, window, options)
+}
+
+fn build_report(
+    symbol: &str,
+    product_key: &Pubkey,
+    onchain_account: &PriceAccount,
+    fetched: Vec<PriceUpdate>,
+    window: Window,
+    options: &TwapOptions,
+) -> Result<TwapReport, &'static str> {
+    let expo = onchain_account.expo;
+    // every update in the window whatever its status, for the status report and the aggregate
+    let mut seen = TwapAccumulator::new(expo);
+    for u in fetched {
+        // check if published outside of a slot window
+        if !window.contains_update(u.pub_slot) {
+            continue;
+        }
+        if options.debug {
+            println!(
+                "{}: p: {}, c: {}, s: {}",
+                u.pub_slot,
+                u.price,
+                u.conf,
+                u.status.name()
+            );
+        }
+        seen.ingest(u);
+    }
+
+    if let Some(path) = &options.export {
+        let updates: Vec<PriceUpdate> = seen.updates().cloned().collect();
+        Export::new(symbol, product_key, onchain_account, &updates).write(path)?;
+    }
+
+    let status = status::report(&seen, window.end());
+
+    // drop empty prices and statuses the policy leaves out
+    let mut accepted = TwapAccumulator::new(expo);
+    for u in seen.updates() {
+        if u.is_valid(&options.status_policy) {
+            accepted.ingest(u.clone());
+        }
+    }
+    let (accumulator, rejections) = options.outliers.apply(&accepted)?;
+
+    let coverage = options
+        .max_gap
+        .map(|threshold| coverage::detect(&accumulator, &window, threshold));
+    if let (Some(c), Some(min)) = (&coverage, &options.min_coverage) {
+        if c.pct() < *min {
+            return Err("window coverage is below the minimum");
+        }
+    }
+
+    // on a small enough interval there may not be enough data especially with pyth in beta
+    let ohlc = match accumulator.ohlc() {
+        Some(ohlc) => ohlc,
+        None => return Err("not enough data to calculate TWAP"),
+    };
+    // each price weighted by how long it was in force until the next update or the window end
+    let twap = match accumulator.average(options.method, window.end()) {
+        Some(twap) => twap,
+        None => return Err("prices in the window cannot be averaged with this method"),
+    };
+    let twac = accumulator
+        .twac(window.end())
+        .ok_or("confidence in the window cannot be averaged")?;
+    let (twap_lower, twap_upper) = accumulator
+        .twap_bands(window.end())
+        .ok_or("confidence band in the window cannot be averaged")?;
+
+    let candles = match options.candle_size {
+        Some(size) => candle::candles(&accumulator, &window, size),
+        None => Vec::new(),
+    };
+
+    // compared against the aggregate the program would have computed, like --reconstruct
+    let publishers = if options.publishers {
+        publisher::breakdown(&accumulator, &seen, options.min_publishers, window.end())
+    } else {
+        Vec::new()
+    };
+
+    let aggregate = if options.reconstruct {
+        aggregate::compare(
+            // the program sees every update and does not filter outliers either
+            aggregate::reconstruct(&seen, options.min_publishers),
+            Decimal::from_price(onchain_account.agg_price, expo),
+            Decimal::new(onchain_account.agg_conf as i128, expo),
+            onchain_account.agg_pub_slot,
+        )
+    } else {
+        None
+    };
+    // a slot window ends at its last slot, a time window at the last slot that landed in it
+    let end_slot = match window {
+        Window::Slots { to, .. } => Some(to),
+        Window::Time { .. } => seen.updates().map(|u| u.slot).max(),
+    };
+    let onchain = match end_slot {
+        Some(end_slot)
+            if onchain_account.agg_pub_slot <= end_slot.saturating_add(MAX_ONCHAIN_LAG) =>
+        {
+            let arithmetic = accumulator
+                .twap(window.end())
+                .ok_or("prices in the window cannot be averaged")?;
+            match OnChainComparison::new(
+                arithmetic,
+                Decimal::from_price(onchain_account.twap, onchain_account.expo),
+                onchain_account.valid_slot,
+            ) {
+                Some(onchain) => Some(onchain),
+                None => return Err("deviation from the on-chain TWAP is out of range"),
+            }
+        }
+        _ => None,
+    };
+
+    Ok(TwapReport {
+        symbol: symbol.to_string(),
+        product_key: *product_key,
+        price_key: onchain_account.key,
+        window,
+        expo,
+        ohlc,
+        method: options.method,
+        twap,
+        twac,
+        twap_lower,
+        twap_upper,
+        onchain,
+        candles,
+        publishers,
+        aggregate,
+        num_updates: accumulator.count(),
+        rejections,
+        status,
+        coverage,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decimal::Decimal;
+    use crate::pyth::{PriceAccount, Status};
+    use crate::report::{compute_twap_from, TwapOptions, MAX_ONCHAIN_LAG};
+    use crate::source::FixtureSource;
+    use crate::twap::{Method, PriceUpdate};
+    use crate::window::Window;
+    use solana_program::pubkey::Pubkey;
+
+    #[test]
+    fn computes_from_fixture() {
+        let update = |slot: u64, price: i64| PriceUpdate {
+            pub_slot: slot,
+            slot,
+            block_time: 1000 + slot as i64,
+            price,
+            conf: 2,
+            status: Status::Trading,
+            publisher: Pubkey::new(&[1; 32]),
+        };
+        let mut source =
+            FixtureSource::new(vec![update(10, 100), update(20, 200), update(40, 900)]);
+        let price_account = PriceAccount {
+            key: Pubkey::new(&[2; 32]),
+            program_id: Pubkey::new(&[3; 32]),
+            expo: -2,
+            twap: 150,
+            valid_slot: 30,
+            agg_price: 200,
+            agg_conf: 2,
+            agg_pub_slot: 20,
+            agg_status: Status::Trading,
+        };
+        let report = compute_twap_from(
+            &mut source,
+            "BTC/USD",
+            &Pubkey::new(&[4; 32]),
+            &price_account,
+            Window::slots(10, 29).unwrap(),
+            &TwapOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(report.num_updates, 2);
+        assert_eq!(report.twap, Decimal::from_price(150, -2));
+        assert_eq!(report.ohlc.high, Decimal::from_price(200, -2));
+        assert!(report.onchain.unwrap().deviation.is_zero());
+        assert_eq!(report.price_key, price_account.key);
+
+        // another method is reported but the on-chain TWAP is still compared to the arithmetic one
+        let median = TwapOptions {
+            method: Method::Median,
+            ..TwapOptions::default()
+        };
+        let report = compute_twap_from(
+            &mut source,
+            "BTC/USD",
+            &Pubkey::new(&[4; 32]),
+            &price_account,
+            Window::slots(10, 29).unwrap(),
+            &median,
+        )
+        .unwrap();
+        assert_eq!(report.twap, Decimal::from_price(100, -2));
+        let onchain = report.onchain.unwrap();
+        assert_eq!(onchain.calculated, Decimal::from_price(150, -2));
+        assert!(onchain.deviation.is_zero());
+
+        // the account moved on long after the window, its TWAP is not the one at the window end
+        let moved_on = PriceAccount {
+            agg_pub_slot: 29 + MAX_ONCHAIN_LAG + 1,
+            ..price_account
+        };
+        let report = compute_twap_from(
+            &mut source,
+            "BTC/USD",
+            &Pubkey::new(&[4; 32]),
+            &moved_on,
+            Window::slots(10, 29).unwrap(),
+            &TwapOptions::default(),
+        )
+        .unwrap();
+        assert!(report.onchain.is_none());
+    }
+}
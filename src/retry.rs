@@ -0,0 +1,182 @@
+use rand::Rng;
+use reqwest::StatusCode;
+use solana_client::rpc_custom_error::{
+    JSON_RPC_SERVER_ERROR_BLOCK_NOT_AVAILABLE, JSON_RPC_SERVER_ERROR_NODE_UNHEALTHY,
+};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// how rpc calls are retried and paced, shared by the blocking and async clients
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    // attempts per call including the first, at least one is always made
+    pub max_attempts: u32,
+    // the wait before the first retry, doubled for every retry after it
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    // a rate limited (http 429) call waits at least this long before it is retried
+    pub rate_limited_delay: Duration,
+    // client side limit on calls per second, None for no limit
+    pub requests_per_second: Option<u32>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(250),
+            max_delay: Duration::from_secs(10),
+            rate_limited_delay: Duration::from_secs(2),
+            requests_per_second: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // exponential backoff capped at max_delay, with a random half taken off
+    // so clients that failed together do not retry together
+    pub fn delay(&self, retry: u32, rate_limited: bool) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(1 << retry.min(16))
+            .min(self.max_delay);
+        let half = backoff / 2;
+        let jitter = rand::thread_rng().gen_range(0, half.as_millis() as u64 + 1);
+        let delay = half + Duration::from_millis(jitter);
+        if rate_limited {
+            return delay.max(self.rate_limited_delay);
+        }
+        delay
+    }
+    // how long to wait after a call failed attempts times in a row, None to give up on it
+    pub fn after(&self, attempts: u32, failure: Failure) -> Option<Duration> {
+        if failure == Failure::Permanent || attempts >= self.max_attempts {
+            return None;
+        }
+        Some(self.delay(attempts.saturating_sub(1), failure == Failure::RateLimited))
+    }
+}
+
+// whether a failed call is worth another attempt
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Failure {
+    // the node or the network had a problem that may pass
+    Transient,
+    // the node asked us to slow down
+    RateLimited,
+    // the same call will fail again
+    Permanent,
+}
+
+impl Failure {
+    // a call the node answered with an http error status
+    pub fn from_status(status: StatusCode) -> Failure {
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            Failure::RateLimited
+        } else if status.is_client_error() {
+            Failure::Permanent
+        } else {
+            Failure::Transient
+        }
+    }
+    // a call the node answered with a json-rpc error
+    pub fn from_rpc_code(code: i64) -> Failure {
+        // a node that is behind or has not got the block yet may catch up
+        match code {
+            JSON_RPC_SERVER_ERROR_NODE_UNHEALTHY | JSON_RPC_SERVER_ERROR_BLOCK_NOT_AVAILABLE => {
+                Failure::Transient
+            }
+            _ => Failure::Permanent,
+        }
+    }
+}
+
+// spaces calls out to at most requests_per_second, across every thread using the client
+pub struct RateLimiter {
+    interval: Option<Duration>,
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_second: Option<u32>) -> RateLimiter {
+        RateLimiter {
+            interval: requests_per_second
+                .filter(|rps| *rps > 0)
+                .map(|rps| Duration::from_secs(1) / rps),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+    // take the next free slot, returns how long to wait for it
+    pub fn reserve(&self) -> Duration {
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return Duration::from_secs(0),
+        };
+        let mut next = self.next.lock().unwrap();
+        let now = Instant::now();
+        let at = (*next).max(now);
+        *next = at + interval;
+        at - now
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::retry::{Failure, RateLimiter, RetryPolicy};
+    use reqwest::StatusCode;
+    use std::time::Duration;
+
+    #[test]
+    fn backs_off_exponentially() {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            ..RetryPolicy::default()
+        };
+        for (retry, full) in [(0, 100), (1, 200), (3, 800), (4, 1000), (30, 1000)] {
+            let delay = policy.delay(retry, false);
+            assert!(delay >= Duration::from_millis(full / 2));
+            assert!(delay <= Duration::from_millis(full));
+        }
+        assert!(policy.delay(0, true) >= policy.rate_limited_delay);
+    }
+    #[test]
+    fn gives_up() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            ..RetryPolicy::default()
+        };
+        assert!(policy.after(1, Failure::Transient).is_some());
+        assert!(policy.after(2, Failure::RateLimited).unwrap() >= policy.rate_limited_delay);
+        assert_eq!(policy.after(3, Failure::Transient), None);
+        assert_eq!(policy.after(1, Failure::Permanent), None);
+    }
+    #[test]
+    fn classifies_responses() {
+        assert_eq!(
+            Failure::from_status(StatusCode::TOO_MANY_REQUESTS),
+            Failure::RateLimited
+        );
+        assert_eq!(
+            Failure::from_status(StatusCode::NOT_FOUND),
+            Failure::Permanent
+        );
+        assert_eq!(
+            Failure::from_status(StatusCode::BAD_GATEWAY),
+            Failure::Transient
+        );
+        assert_eq!(Failure::from_rpc_code(-32005), Failure::Transient);
+        assert_eq!(Failure::from_rpc_code(-32602), Failure::Permanent);
+    }
+    #[test]
+    fn spaces_requests() {
+        let unlimited = RateLimiter::new(None);
+        assert_eq!(unlimited.reserve(), Duration::from_secs(0));
+        let limiter = RateLimiter::new(Some(10));
+        limiter.reserve();
+        let second = limiter.reserve();
+        let third = limiter.reserve();
+        assert!(second > Duration::from_millis(50) && second <= Duration::from_millis(100));
+        assert!(third > Duration::from_millis(150) && third <= Duration::from_millis(200));
+    }
+}
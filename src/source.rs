@@ -0,0 +1,311 @@
+use crate::cache::{Cache, UpdateRecord};
+use crate::fetch::{self, DEFAULT_CONCURRENCY};
+use crate::pyth::{PriceAccount, PythClient, Status};
+use crate::twap::PriceUpdate;
+use crate::window::{Position, Window};
+use progress_bar::color::{Color, Style};
+use progress_bar::progress_bar::ProgressBar;
+use serde::{Deserialize, Serialize};
+use solana_program::pubkey::Pubkey;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// where the decoded updates of a price account come from
+pub trait PriceUpdateSource {
+    // every update of a transaction that landed in the window, in any order
+    // updates published outside a slot window are left for the caller to drop
+    fn updates(
+        &mut self,
+        price_account: &PriceAccount,
+        window: &Window,
+    ) -> Result<Vec<PriceUpdate>, &'static str>;
+}
+
+// fetches the price account transactions from an rpc node
+pub struct RpcSource {
+    pyth: Arc<PythClient>,
+    // number of transactions fetched at once
+    pub concurrency: usize,
+    // keep fetched transactions here and only fetch what it does not hold
+    pub cache: Option<Cache>,
+    pub debug: bool,
+    // draw a progress bar while walking the signatures
+    pub progress: bool,
+}
+
+impl RpcSource {
+    pub fn new(pyth: Arc<PythClient>) -> RpcSource {
+        RpcSource {
+            pyth,
+            concurrency: DEFAULT_CONCURRENCY,
+            cache: None,
+            debug: false,
+            progress: false,
+        }
+    }
+}
+
+impl PriceUpdateSource for RpcSource {
+    fn updates(
+        &mut self,
+        price_account: &PriceAccount,
+        window: &Window,
+    ) -> Result<Vec<PriceUpdate>, &'static str> {
+        let mut progress_bar = if self.progress {
+            println!();
+            println!("Parsing price account transactions");
+            let mut p = ProgressBar::new(100);
+            p.set_action(" Progress", Color::Blue, Style::Bold);
+            Some(p)
+        } else {
+            None
+        };
+
+        let mut cached = self.cache.as_ref().map(|c| c.load(&price_account.key));
+        let fetched = fetch::fetch_updates(
+            &self.pyth,
+            price_account,
+            *window,
+            self.concurrency,
+            cached.as_mut(),
+            self.debug,
+            |done| {
+                if let Some(p) = progress_bar.as_mut() {
+                    p.set_progression((100.0 * done) as usize);
+                }
+            },
+        )?;
+        if let Some(p) = progress_bar.as_mut() {
+            p.set_progression(100);
+        }
+        // the updates are already fetched, a cache that cannot be written only costs the next run
+        if let (Some(cache), Some(cached)) = (&self.cache, &cached) {
+            if let Err(error) = cache.store(cached) {
+                if self.debug {
+                    println!("Cache Err: {}", error);
+                }
+            }
+        }
+        Ok(fetched)
+    }
+}
+
+// the updates of a window written out with the price account as it was when they were fetched,
+// enough to calculate the TWAP again without an rpc node
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Export {
+    pub symbol: String,
+    pub product_key: String,
+    pub price_key: String,
+    pub program_id: String,
+    pub expo: i32,
+    pub twap: i64,
+    pub valid_slot: u64,
+    pub agg_price: i64,
+    pub agg_conf: u64,
+    pub agg_pub_slot: u64,
+    pub agg_status: String,
+    pub updates: Vec<UpdateRecord>,
+}
+
+impl Export {
+    pub fn new(
+        symbol: &str,
+        product_key: &Pubkey,
+        price_account: &PriceAccount,
+        updates: &[PriceUpdate],
+    ) -> Export {
+        Export {
+            symbol: symbol.to_string(),
+            product_key: product_key.to_string(),
+            price_key: price_account.key.to_string(),
+            program_id: price_account.program_id.to_string(),
+            expo: price_account.expo,
+            twap: price_account.twap,
+            valid_slot: price_account.valid_slot,
+            agg_price: price_account.agg_price,
+            agg_conf: price_account.agg_conf,
+            agg_pub_slot: price_account.agg_pub_slot,
+            agg_status: price_account.agg_status.name().to_string(),
+            updates: updates.iter().map(UpdateRecord::new).collect(),
+        }
+    }
+    pub fn write(&self, path: &Path) -> Result<(), &'static str> {
+        let data = match serde_json::to_vec(self) {
+            Ok(data) => data,
+            Err(_) => return Err("cannot serialize the updates"),
+        };
+        match fs::write(path, data) {
+            Ok(_) => Ok(()),
+            Err(_) => Err("cannot write the export file"),
+        }
+    }
+    pub fn read(path: &Path) -> Result<Export, &'static str> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(_) => return Err("cannot read the export file"),
+        };
+        match serde_json::from_slice(&data) {
+            Ok(export) => Ok(export),
+            Err(_) => Err("not a valid export file"),
+        }
+    }
+    pub fn product_key(&self) -> Result<Pubkey, &'static str> {
+        match Pubkey::from_str(&self.product_key) {
+            Ok(key) => Ok(key),
+            Err(_) => Err("not a valid product key"),
+        }
+    }
+    pub fn price_account(&self) -> Result<PriceAccount, &'static str> {
+        let key = |k: &str| match Pubkey::from_str(k) {
+            Ok(key) => Ok(key),
+            Err(_) => Err("not a valid price account key"),
+        };
+        let agg_status = match Status::parse(&self.agg_status) {
+            Some(status) => status,
+            None => return Err("not a valid price status"),
+        };
+        Ok(PriceAccount {
+            key: key(&self.price_key)?,
+            program_id: key(&self.program_id)?,
+            expo: self.expo,
+            twap: self.twap,
+            valid_slot: self.valid_slot,
+            agg_price: self.agg_price,
+            agg_conf: self.agg_conf,
+            agg_pub_slot: self.agg_pub_slot,
+            agg_status,
+        })
+    }
+}
+
+// replays the updates of an export file
+pub struct FileSource {
+    pub export: Export,
+}
+
+impl FileSource {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<FileSource, &'static str> {
+        Ok(FileSource {
+            export: Export::read(path.as_ref())?,
+        })
+    }
+}
+
+impl PriceUpdateSource for FileSource {
+    fn updates(
+        &mut self,
+        price_account: &PriceAccount,
+        window: &Window,
+    ) -> Result<Vec<PriceUpdate>, &'static str> {
+        if self.export.price_key != price_account.key.to_string() {
+            return Err("export file is for another price account");
+        }
+        let mut updates = Vec::with_capacity(self.export.updates.len());
+        for record in &self.export.updates {
+            let u = record.update()?;
+            if landed_in(&u, window) {
+                updates.push(u);
+            }
+        }
+        Ok(updates)
+    }
+}
+
+// serves updates held in memory, for tests and for updates gathered some other way
+#[derive(Default)]
+pub struct FixtureSource {
+    pub updates: Vec<PriceUpdate>,
+}
+
+impl FixtureSource {
+    pub fn new(updates: Vec<PriceUpdate>) -> FixtureSource {
+        FixtureSource { updates }
+    }
+}
+
+impl PriceUpdateSource for FixtureSource {
+    fn updates(
+        &mut self,
+        _price_account: &PriceAccount,
+        window: &Window,
+    ) -> Result<Vec<PriceUpdate>, &'static str> {
+        Ok(self
+            .updates
+            .iter()
+            .filter(|u| landed_in(u, window))
+            .cloned()
+            .collect())
+    }
+}
+
+// the same selection the rpc source makes by the signature of the transaction
+fn landed_in(u: &PriceUpdate, window: &Window) -> bool {
+    window.locate(u.slot, u.block_time) == Position::Inside
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pyth::{PriceAccount, Status};
+    use crate::source::{Export, FileSource, FixtureSource, PriceUpdateSource};
+    use crate::twap::PriceUpdate;
+    use crate::window::Window;
+    use solana_program::pubkey::Pubkey;
+    use std::env;
+    use std::fs;
+
+    fn update(slot: u64, price: i64) -> PriceUpdate {
+        PriceUpdate {
+            pub_slot: slot,
+            slot,
+            block_time: 1000 + slot as i64,
+            price,
+            conf: 1,
+            status: Status::Trading,
+            publisher: Pubkey::new(&[1; 32]),
+        }
+    }
+
+    fn price_account(key: Pubkey) -> PriceAccount {
+        PriceAccount {
+            key,
+            program_id: Pubkey::new(&[2; 32]),
+            expo: -2,
+            twap: 150,
+            valid_slot: 30,
+            agg_price: 200,
+            agg_conf: 1,
+            agg_pub_slot: 30,
+            agg_status: Status::Trading,
+        }
+    }
+
+    #[test]
+    fn replays_the_window() {
+        let account = price_account(Pubkey::new(&[3; 32]));
+        let updates = vec![update(10, 100), update(20, 200), update(30, 300)];
+        let window = Window::slots(15, 30).unwrap();
+        let mut fixture = FixtureSource::new(updates.clone());
+        let selected = fixture.updates(&account, &window).unwrap();
+        assert_eq!(selected.len(), 2);
+        assert_eq!(selected[0].price, 200);
+
+        let path = env::temp_dir().join(format!("pyth-twap-export-{}.json", std::process::id()));
+        let export = Export::new("BTC/USD", &Pubkey::new(&[4; 32]), &account, &updates);
+        export.write(&path).unwrap();
+        let mut file = FileSource::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(file.export, export);
+        let replayed = file.export.price_account().unwrap();
+        assert_eq!(replayed.key, account.key);
+        assert_eq!(replayed.twap, 150);
+        let selected = file.updates(&replayed, &window).unwrap();
+        assert_eq!(selected.len(), 2);
+        assert_eq!(selected[1].pub_slot, 30);
+
+        let other = price_account(Pubkey::new(&[5; 32]));
+        assert!(file.updates(&other, &window).is_err());
+    }
+}
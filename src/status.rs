@@ -0,0 +1,269 @@
+use crate::pyth::Status;
+use crate::twap::{PriceUpdate, TwapAccumulator};
+use crate::window::WindowEnd;
+use solana_program::pubkey::Pubkey;
+use std::collections::BTreeMap;
+
+// which publisher statuses count towards the TWAP, trading always does
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StatusPolicy {
+    pub unknown: bool,
+    pub halted: bool,
+    pub auction: bool,
+}
+
+impl StatusPolicy {
+    // comma separated statuses to include besides trading, like "halted,auction"
+    pub fn parse(list: &str) -> Result<StatusPolicy, &'static str> {
+        let mut policy = StatusPolicy::default();
+        for name in list.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            match name {
+                "trading" => {}
+                "unknown" => policy.unknown = true,
+                "halted" => policy.halted = true,
+                "auction" => policy.auction = true,
+                _ => return Err("status should be one of trading, unknown, halted or auction"),
+            }
+        }
+        Ok(policy)
+    }
+    pub fn includes(&self, status: Status) -> bool {
+        match status {
+            Status::Trading => true,
+            Status::Unknown => self.unknown,
+            Status::Halted => self.halted,
+            Status::Auction => self.auction,
+        }
+    }
+}
+
+// a publisher changed status with this update
+pub struct StatusTransition {
+    pub pub_slot: u64,
+    pub block_time: i64,
+    pub publisher: Pubkey,
+    pub from: Status,
+    pub to: Status,
+}
+
+pub struct StatusReport {
+    // wall clock seconds (or slots for a slot window) the feed spent in each status, trading
+    // first, from the first update of the window to its end
+    pub durations: Vec<(Status, i64)>,
+    // ordered by publish slot, then publisher
+    pub transitions: Vec<StatusTransition>,
+}
+
+impl StatusReport {
+    pub fn total(&self) -> i64 {
+        self.durations.iter().map(|(_, d)| d).sum()
+    }
+    // time spent in anything but trading
+    pub fn not_trading(&self) -> i64 {
+        self.durations
+            .iter()
+            .filter(|(s, _)| *s != Status::Trading)
+            .map(|(_, d)| d)
+            .sum()
+    }
+}
+
+// the feed is trading while any publisher is, otherwise in the status most publishers are in
+// (halted, then auction, then unknown on a tie)
+fn feed_status(current: &BTreeMap<Pubkey, Status>) -> Status {
+    let count = |status: Status| current.values().filter(|s| **s == status).count();
+    if count(Status::Trading) > 0 {
+        return Status::Trading;
+    }
+    let mut feed = Status::Halted;
+    for status in [Status::Auction, Status::Unknown] {
+        if count(status) > count(feed) {
+            feed = status;
+        }
+    }
+    feed
+}
+
+// each publisher has its own status, in force from the update that reported it until
+// the publisher's next update, the same way a price is for the TWAP, whatever the policy leaves out
+// the feed status is taken from the publisher statuses in force at each point of the window
+pub fn report(acc: &TwapAccumulator, end: WindowEnd) -> StatusReport {
+    let mut per_publisher: BTreeMap<Pubkey, TwapAccumulator> = BTreeMap::new();
+    for u in acc.updates() {
+        per_publisher
+            .entry(u.publisher)
+            .or_insert_with(|| TwapAccumulator::new(acc.expo()))
+            .ingest(u.clone());
+    }
+    let position = |u: &PriceUpdate| match end {
+        WindowEnd::Time(_) => u.block_time,
+        WindowEnd::Slot(_) => u.pub_slot as i64,
+    };
+
+    // every status change of every publisher, ordered by when it happened
+    let mut changes = Vec::new();
+    let mut transitions = Vec::new();
+    for updates in per_publisher.values() {
+        let mut current: Option<Status> = None;
+        for (u, _) in updates.durations(end) {
+            if let Some(from) = current {
+                if from != u.status {
+                    transitions.push(StatusTransition {
+                        pub_slot: u.pub_slot,
+                        block_time: u.block_time,
+                        publisher: u.publisher,
+                        from,
+                        to: u.status,
+                    });
+                }
+            }
+            current = Some(u.status);
+            changes.push((position(&u), u.publisher, u.status));
+        }
+    }
+    changes.sort_by_key(|(at, publisher, _)| (*at, *publisher));
+    transitions.sort_by_key(|t| (t.pub_slot, t.publisher));
+
+    let mut durations: Vec<(Status, i64)> = vec![
+        (Status::Trading, 0),
+        (Status::Halted, 0),
+        (Status::Auction, 0),
+        (Status::Unknown, 0),
+    ];
+    let mut add = |status: Status, duration: i64| {
+        if let Some(entry) = durations.iter_mut().find(|(s, _)| *s == status) {
+            entry.1 = entry.1.saturating_add(duration.max(0));
+        }
+    };
+    let mut current: BTreeMap<Pubkey, Status> = BTreeMap::new();
+    let mut since: Option<i64> = None;
+    for (at, publisher, status) in changes {
+        if let Some(since) = since {
+            add(feed_status(&current), at.saturating_sub(since));
+        }
+        current.insert(publisher, status);
+        since = Some(at);
+    }
+    if let Some(since) = since {
+        let end = match end {
+            WindowEnd::Time(t) => t,
+            WindowEnd::Slot(s) => s as i64,
+        };
+        add(feed_status(&current), end.saturating_sub(since));
+    }
+    StatusReport {
+        durations,
+        transitions,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pyth::Status;
+    use crate::status::{report, StatusPolicy};
+    use crate::twap::{PriceUpdate, TwapAccumulator};
+    use crate::window::WindowEnd;
+    use solana_program::pubkey::Pubkey;
+
+    #[test]
+    fn parses_policy() {
+        assert_eq!(StatusPolicy::parse(""), Ok(StatusPolicy::default()));
+        let policy = StatusPolicy::parse("halted, auction").unwrap();
+        assert!(policy.includes(Status::Trading));
+        assert!(policy.includes(Status::Halted));
+        assert!(!policy.includes(Status::Unknown));
+        assert!(StatusPolicy::parse("closed").is_err());
+    }
+    #[test]
+    fn times_each_status() {
+        let mut acc = TwapAccumulator::new(0);
+        for (slot, status) in [
+            (1, Status::Trading),
+            (2, Status::Trading),
+            (3, Status::Halted),
+            (4, Status::Halted),
+            (5, Status::Trading),
+        ] {
+            acc.ingest(PriceUpdate {
+                pub_slot: slot,
+                slot,
+                block_time: slot as i64 * 10,
+                price: 100,
+                conf: 1,
+                status,
+                publisher: Pubkey::default(),
+            });
+        }
+        let status = report(&acc, WindowEnd::Time(80));
+        assert_eq!(status.durations[0], (Status::Trading, 50));
+        assert_eq!(status.durations[1], (Status::Halted, 20));
+        assert_eq!((status.total(), status.not_trading()), (70, 20));
+        let changes: Vec<(u64, Status)> = status
+            .transitions
+            .iter()
+            .map(|t| (t.pub_slot, t.to))
+            .collect();
+        assert_eq!(changes, vec![(3, Status::Halted), (5, Status::Trading)]);
+    }
+    #[test]
+    fn tracks_each_publisher() {
+        let a = Pubkey::new(&[1; 32]);
+        let b = Pubkey::new(&[2; 32]);
+        let mut acc = TwapAccumulator::new(0);
+        // a keeps trading while b is halted, then b resumes at slot 4
+        for (slot, publisher, status) in [
+            (1, a, Status::Trading),
+            (1, b, Status::Halted),
+            (2, a, Status::Trading),
+            (2, b, Status::Halted),
+            (3, a, Status::Trading),
+            (4, b, Status::Trading),
+        ] {
+            acc.ingest(PriceUpdate {
+                pub_slot: slot,
+                slot,
+                block_time: slot as i64 * 10,
+                price: 100,
+                conf: 1,
+                status,
+                publisher,
+            });
+        }
+        let status = report(&acc, WindowEnd::Time(50));
+        // b is halted for 30s while a keeps the feed trading for all 40s
+        assert_eq!(status.durations[0], (Status::Trading, 40));
+        assert_eq!(status.durations[1], (Status::Halted, 0));
+        assert_eq!(status.transitions.len(), 1);
+        assert_eq!(status.transitions[0].publisher, b);
+        assert_eq!(status.transitions[0].pub_slot, 4);
+    }
+    #[test]
+    fn halts_the_feed_when_nobody_trades() {
+        let a = Pubkey::new(&[1; 32]);
+        let b = Pubkey::new(&[2; 32]);
+        let mut acc = TwapAccumulator::new(0);
+        // both halt at slot 2, a resumes at slot 4
+        for (slot, publisher, status) in [
+            (1, a, Status::Trading),
+            (1, b, Status::Trading),
+            (2, a, Status::Halted),
+            (2, b, Status::Halted),
+            (4, a, Status::Trading),
+        ] {
+            acc.ingest(PriceUpdate {
+                pub_slot: slot,
+                slot,
+                block_time: slot as i64 * 10,
+                price: 100,
+                conf: 1,
+                status,
+                publisher,
+            });
+        }
+        let status = report(&acc, WindowEnd::Time(50));
+        // the window from the first update is 40s, 20s of it halted
+        assert_eq!(status.total(), 40);
+        assert_eq!(status.durations[0], (Status::Trading, 20));
+        assert_eq!(status.durations[1], (Status::Halted, 20));
+    }
+}
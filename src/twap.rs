@@ -0,0 +1,514 @@
+use crate::decimal::Decimal;
+use crate::pyth::Status;
+use crate::status::StatusPolicy;
+use crate::window::WindowEnd;
+use solana_program::pubkey::Pubkey;
+use std::collections::BTreeMap;
+
+// a single decoded price update from a price account transaction
+#[derive(Clone, Debug)]
+pub struct PriceUpdate {
+    pub pub_slot: u64,
+    // slot and time of the block the transaction landed in
+    pub slot: u64,
+    pub block_time: i64,
+    pub price: i64,
+    pub conf: u64,
+    pub status: Status,
+    // the signer of the update instruction
+    pub publisher: Pubkey,
+}
+
+impl PriceUpdate {
+    // whether the update counts towards the TWAP under the status policy
+    pub fn is_valid(&self, policy: &StatusPolicy) -> bool {
+        if !policy.includes(self.status) {
+            return false;
+        }
+        if self.price == 0 {
+            return false;
+        }
+        true
+    }
+}
+
+// how the prices in the window are averaged
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Method {
+    // duration weighted arithmetic mean
+    Arithmetic,
+    // duration weighted mean of log prices, like the uniswap v3 oracle
+    Geometric,
+    // the price that was in force for half of the window
+    Median,
+    // exponential moving average at the window end, half life in seconds or slots
+    Ema { half_life: i64 },
+    // duration weighted mean with 1/conf^2 (inverse variance) weights
+    ConfidenceWeighted,
+}
+
+impl Method {
+    pub fn parse(name: &str, half_life: Option<i64>) -> Result<Method, &'static str> {
+        match name {
+            "arithmetic" => Ok(Method::Arithmetic),
+            "geometric" => Ok(Method::Geometric),
+            "median" => Ok(Method::Median),
+            "ema" => match half_life {
+                Some(h) if h > 0 => Ok(Method::Ema { half_life: h }),
+                _ => Err("ema needs a positive half life"),
+            },
+            "conf" => Ok(Method::ConfidenceWeighted),
+            _ => Err("method should be one of arithmetic, geometric, median, ema or conf"),
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Method::Arithmetic => "arithmetic",
+            Method::Geometric => "geometric",
+            Method::Median => "median",
+            Method::Ema { .. } => "ema",
+            Method::ConfidenceWeighted => "conf",
+        }
+    }
+}
+
+pub struct Ohlc {
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    pub open_slot: u64,
+    pub close_slot: u64,
+}
+
+// collects price updates in any order, keyed by publish slot and publisher
+// so the same update seen twice (or from two fetchers) only counts once
+#[derive(Clone)]
+pub struct TwapAccumulator {
+    expo: i32,
+    updates: BTreeMap<(u64, Pubkey), PriceUpdate>,
+    duplicates: usize,
+}
+
+impl TwapAccumulator {
+    pub fn new(expo: i32) -> TwapAccumulator {
+        TwapAccumulator {
+            expo,
+            updates: BTreeMap::new(),
+            duplicates: 0,
+        }
+    }
+    pub fn expo(&self) -> i32 {
+        self.expo
+    }
+    // returns false if the publisher already has an update for the slot
+    pub fn ingest(&mut self, update: PriceUpdate) -> bool {
+        let key = (update.pub_slot, update.publisher);
+        if self.updates.contains_key(&key) {
+            self.duplicates += 1;
+            return false;
+        }
+        self.updates.insert(key, update);
+        true
+    }
+    pub fn merge(&mut self, other: &TwapAccumulator) -> Result<(), &'static str> {
+        if other.expo != self.expo {
+            return Err("cannot merge accumulators with different exponents");
+        }
+        for update in other.updates.values() {
+            self.ingest(update.clone());
+        }
+        self.duplicates += other.duplicates;
+        Ok(())
+    }
+    pub fn count(&self) -> usize {
+        self.updates.len()
+    }
+    pub fn duplicates(&self) -> usize {
+        self.duplicates
+    }
+    pub fn is_empty(&self) -> bool {
+        self.updates.is_empty()
+    }
+    // accepted updates ordered by publish slot, then publisher
+    pub fn updates(&self) -> impl Iterator<Item = &PriceUpdate> {
+        self.updates.values()
+    }
+    // one price per publish slot, the median price and conf of the publishers that
+    // updated in it, so no publisher decides the slot by the order of its key
+    pub fn slot_prices(&self) -> Vec<PriceUpdate> {
+        let mut slots: Vec<PriceUpdate> = Vec::new();
+        let mut components: Vec<&PriceUpdate> = Vec::new();
+        let mut it = self.updates.values().peekable();
+        while let Some(u) = it.next() {
+            components.push(u);
+            if matches!(it.peek(), Some(next) if next.pub_slot == u.pub_slot) {
+                continue;
+            }
+            let mut slot = components[0].clone();
+            if components.len() > 1 {
+                slot.price = median(components.iter().map(|c| c.price as i128).collect()) as i64;
+                slot.conf = median(components.iter().map(|c| c.conf as i128).collect()) as u64;
+            }
+            slots.push(slot);
+            components.clear();
+        }
+        slots
+    }
+    pub fn ohlc(&self) -> Option<Ohlc> {
+        let slots = self.slot_prices();
+        let first = slots.first()?;
+        let last = slots.last()?;
+        let high = slots.iter().map(|u| u.price).max()?;
+        let low = slots.iter().map(|u| u.price).min()?;
+        Some(Ohlc {
+            open: Decimal::from_price(first.price, self.expo),
+            high: Decimal::from_price(high, self.expo),
+            low: Decimal::from_price(low, self.expo),
+            close: Decimal::from_price(last.price, self.expo),
+            open_slot: first.pub_slot,
+            close_slot: last.pub_slot,
+        })
+    }
+    // how long each slot price stayed in force, in seconds for time windows and slots for slot windows
+    // each price lasts until the next slot, the latest one is held until the end of the window
+    pub fn durations(&self, end: WindowEnd) -> Vec<(PriceUpdate, i64)> {
+        let position = |u: &PriceUpdate| match end {
+            WindowEnd::Time(_) => u.block_time,
+            WindowEnd::Slot(_) => u.pub_slot as i64,
+        };
+        let end = match end {
+            WindowEnd::Time(t) => t,
+            WindowEnd::Slot(s) => s as i64,
+        };
+        let slots = self.slot_prices();
+        let mut durations = Vec::with_capacity(slots.len());
+        let mut it = slots.into_iter().peekable();
+        while let Some(u) = it.next() {
+            let until = match it.peek() {
+                Some(next) => position(next),
+                None => end,
+            };
+            let duration = until.saturating_sub(position(&u)).max(0);
+            durations.push((u, duration));
+        }
+        durations
+    }
+    // time weighted average at the price account exponent
+    pub fn twap(&self, end: WindowEnd) -> Option<Decimal> {
+        self.weighted_mean(end, |u| u.price as i128)
+    }
+    // time weighted average confidence, pyth keeps the same on-chain as twac
+    pub fn twac(&self, end: WindowEnd) -> Option<Decimal> {
+        self.weighted_mean(end, |u| u.conf as i128)
+    }
+    // TWAP of the lower (price - conf) and upper (price + conf) edges of the confidence band
+    pub fn twap_bands(&self, end: WindowEnd) -> Option<(Decimal, Decimal)> {
+        let lower = self.weighted_mean(end, |u| u.price as i128 - u.conf as i128)?;
+        let upper = self.weighted_mean(end, |u| u.price as i128 + u.conf as i128)?;
+        Some((lower, upper))
+    }
+    fn weighted_mean<F>(&self, end: WindowEnd, value: F) -> Option<Decimal>
+    where
+        F: Fn(&PriceUpdate) -> i128,
+    {
+        let durations = self.durations(end);
+        let last = &durations.last()?.0;
+
+        // None when the sums do not fit
+        let mut weighted_sum: i128 = 0;
+        let mut total_duration: i128 = 0;
+        for (u, duration) in &durations {
+            weighted_sum = weighted_sum.checked_add(value(u).checked_mul(*duration as i128)?)?;
+            total_duration = total_duration.checked_add(*duration as i128)?;
+        }
+
+        // every update landed at the very end of the window
+        // so the latest price is the only one that was ever in force
+        if total_duration == 0 {
+            return Some(Decimal::new(value(last), self.expo));
+        }
+        Decimal::new(weighted_sum, self.expo).div_int(total_duration, self.expo)
+    }
+    pub fn average(&self, method: Method, end: WindowEnd) -> Option<Decimal> {
+        match method {
+            Method::Arithmetic => self.twap(end),
+            Method::Geometric => self.geometric_twap(end),
+            Method::Median => self.median(end),
+            Method::Ema { half_life } => self.ema(end, half_life),
+            Method::ConfidenceWeighted => self.conf_weighted(end),
+        }
+    }
+    // the methods below go through f64 and are rounded back to the price exponent,
+    // so unlike the arithmetic TWAP they are only exact to about 15 significant digits
+    pub fn geometric_twap(&self, end: WindowEnd) -> Option<Decimal> {
+        let durations = self.durations(end);
+        if durations.iter().any(|(u, _)| u.price <= 0) {
+            return None;
+        }
+        let total: i128 = durations.iter().map(|(_, d)| *d as i128).sum();
+        let log_price = if total == 0 {
+            (durations.last()?.0.price as f64).ln()
+        } else {
+            durations
+                .iter()
+                .map(|(u, d)| (u.price as f64).ln() * *d as f64)
+                .sum::<f64>()
+                / total as f64
+        };
+        Some(Decimal::new(log_price.exp().round() as i128, self.expo))
+    }
+    pub fn median(&self, end: WindowEnd) -> Option<Decimal> {
+        let mut durations = self.durations(end);
+        let last = durations.last()?.0.price;
+        let total: i128 = durations.iter().map(|(_, d)| *d as i128).sum();
+        if total == 0 {
+            return Some(Decimal::from_price(last, self.expo));
+        }
+        durations.sort_by_key(|(u, _)| u.price);
+        let mut cumulative: i128 = 0;
+        for (u, d) in durations {
+            cumulative += d as i128;
+            if cumulative * 2 >= total {
+                return Some(Decimal::from_price(u.price, self.expo));
+            }
+        }
+        None
+    }
+    pub fn ema(&self, end: WindowEnd, half_life: i64) -> Option<Decimal> {
+        let durations = self.durations(end);
+        let mut ema = durations.first()?.0.price as f64;
+        for (u, d) in durations {
+            // the price pulls the average towards it for as long as it is in force
+            let decay = 0.5f64.powf(d as f64 / half_life as f64);
+            ema = u.price as f64 + (ema - u.price as f64) * decay;
+        }
+        Some(Decimal::new(ema.round() as i128, self.expo))
+    }
+    pub fn conf_weighted(&self, end: WindowEnd) -> Option<Decimal> {
+        let durations = self.durations(end);
+        let last = durations.last()?.0.price;
+        let mut weighted_sum = 0.0;
+        let mut total_weight = 0.0;
+        for (u, d) in durations {
+            // a zero conf is as certain as a price can be, treat it as one unit
+            let conf = u.conf.max(1) as f64;
+            let weight = d as f64 / (conf * conf);
+            weighted_sum += u.price as f64 * weight;
+            total_weight += weight;
+        }
+        if total_weight == 0.0 {
+            return Some(Decimal::from_price(last, self.expo));
+        }
+        let average = weighted_sum / total_weight;
+        Some(Decimal::new(average.round() as i128, self.expo))
+    }
+}
+
+// the middle value, or the mean of the two middle values of an even count
+fn median(mut values: Vec<i128>) -> i128 {
+    values.sort_unstable();
+    let n = values.len();
+    if n % 2 == 1 {
+        values[n / 2]
+    } else {
+        (values[n / 2 - 1] + values[n / 2]) / 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decimal::Decimal;
+    use crate::pyth::Status;
+    use crate::status::StatusPolicy;
+    use crate::twap::{Method, PriceUpdate, TwapAccumulator};
+    use crate::window::WindowEnd;
+    use solana_program::pubkey::Pubkey;
+
+    fn update(pub_slot: u64, block_time: i64, price: i64) -> PriceUpdate {
+        PriceUpdate {
+            pub_slot,
+            slot: pub_slot,
+            block_time,
+            price,
+            conf: 0,
+            status: Status::Trading,
+            publisher: Pubkey::default(),
+        }
+    }
+    fn accumulate(expo: i32, updates: Vec<PriceUpdate>) -> TwapAccumulator {
+        let mut acc = TwapAccumulator::new(expo);
+        for u in updates {
+            acc.ingest(u);
+        }
+        acc
+    }
+
+    #[test]
+    fn validates_by_policy() {
+        let u = update(1, 0, 100);
+        assert!(u.is_valid(&StatusPolicy::default()));
+        let mut halted = u.clone();
+        halted.status = Status::Halted;
+        assert!(!halted.is_valid(&StatusPolicy::default()));
+        assert!(halted.is_valid(&StatusPolicy::parse("halted").unwrap()));
+        assert!(!update(1, 0, 0).is_valid(&StatusPolicy::default()));
+    }
+    #[test]
+    fn weights_by_duration() {
+        // 100 for 30s, 200 for 10s
+        let acc = accumulate(0, vec![update(2, 130, 200), update(1, 100, 100)]);
+        assert_eq!(
+            acc.twap(WindowEnd::Time(140)).unwrap(),
+            Decimal::new(125, 0)
+        );
+    }
+    #[test]
+    fn rounds_at_price_exponent() {
+        // (100 * 2 + 101 * 1) / 3 = 100.333...
+        let acc = accumulate(-2, vec![update(1, 0, 100), update(2, 2, 101)]);
+        let twap = acc.twap(WindowEnd::Time(3)).unwrap();
+        assert_eq!(twap.to_string(), "1.00");
+        assert_eq!(twap.mantissa, 100);
+    }
+    #[test]
+    fn empty_window() {
+        let acc = TwapAccumulator::new(-8);
+        assert_eq!(acc.twap(WindowEnd::Time(100)), None);
+        assert!(acc.ohlc().is_none());
+    }
+    #[test]
+    fn overflow_is_none() {
+        let mut a = update(1, i64::MIN + 1, i64::MAX);
+        a.conf = u64::MAX;
+        let mut b = update(2, 0, i64::MAX);
+        b.conf = u64::MAX;
+        let acc = accumulate(0, vec![a, b]);
+        // the upper band weighted by the whole i64 range does not fit an i128
+        assert_eq!(acc.twap_bands(WindowEnd::Time(i64::MAX)), None);
+        assert!(acc.twap(WindowEnd::Time(i64::MAX)).is_some());
+    }
+    #[test]
+    fn zero_duration() {
+        let acc = accumulate(-1, vec![update(1, 100, 100), update(2, 100, 300)]);
+        assert_eq!(acc.twap(WindowEnd::Time(100)).unwrap().to_string(), "30.0");
+    }
+    #[test]
+    fn ohlc_out_of_order() {
+        let acc = accumulate(
+            0,
+            vec![update(3, 30, 90), update(1, 10, 100), update(2, 20, 120)],
+        );
+        let ohlc = acc.ohlc().unwrap();
+        assert_eq!(ohlc.open, Decimal::new(100, 0));
+        assert_eq!(ohlc.high, Decimal::new(120, 0));
+        assert_eq!(ohlc.low, Decimal::new(90, 0));
+        assert_eq!(ohlc.close, Decimal::new(90, 0));
+        assert_eq!((ohlc.open_slot, ohlc.close_slot), (1, 3));
+    }
+    #[test]
+    fn merge_deduplicates_slots() {
+        let mut a = accumulate(0, vec![update(1, 10, 100), update(2, 20, 110)]);
+        let b = accumulate(0, vec![update(2, 20, 110), update(3, 30, 120)]);
+        a.merge(&b).unwrap();
+        assert_eq!(a.count(), 3);
+        assert_eq!(a.duplicates(), 1);
+        assert!(a.merge(&TwapAccumulator::new(-8)).is_err());
+    }
+    #[test]
+    fn keeps_publishers_in_the_same_slot() {
+        let mut a = update(1, 10, 100);
+        a.publisher = Pubkey::new(&[1; 32]);
+        let mut b = update(1, 10, 102);
+        b.publisher = Pubkey::new(&[2; 32]);
+        let acc = accumulate(0, vec![a.clone(), b, a]);
+        assert_eq!(acc.count(), 2);
+        assert_eq!(acc.duplicates(), 1);
+    }
+    #[test]
+    fn takes_the_median_of_a_slot() {
+        // two publishers in slot 1, the one with the higher key does not decide the price
+        let mut a = update(1, 0, 100);
+        a.publisher = Pubkey::new(&[1; 32]);
+        let mut b = update(1, 0, 200);
+        b.publisher = Pubkey::new(&[2; 32]);
+        let mut c = update(2, 10, 300);
+        c.publisher = Pubkey::new(&[1; 32]);
+        let acc = accumulate(0, vec![a, b, c]);
+        // 150 for 10s, 300 for 10s
+        assert_eq!(acc.twap(WindowEnd::Time(20)).unwrap(), Decimal::new(225, 0));
+        let ohlc = acc.ohlc().unwrap();
+        assert_eq!(ohlc.open, Decimal::new(150, 0));
+        assert_eq!(ohlc.low, Decimal::new(150, 0));
+        assert_eq!(acc.durations(WindowEnd::Time(20)).len(), 2);
+    }
+    #[test]
+    fn weights_by_slot() {
+        // 100 for slots 10-19, 200 for slots 20-49
+        let acc = accumulate(0, vec![update(10, 0, 100), update(20, 0, 200)]);
+        assert_eq!(acc.twap(WindowEnd::Slot(50)).unwrap(), Decimal::new(175, 0));
+    }
+    #[test]
+    fn median_by_duration() {
+        // 100 for 10s, 300 for 50s, 200 for 20s
+        let acc = accumulate(
+            0,
+            vec![update(1, 0, 100), update(2, 10, 300), update(3, 60, 200)],
+        );
+        let median = acc.average(Method::Median, WindowEnd::Time(80)).unwrap();
+        assert_eq!(median, Decimal::new(300, 0));
+    }
+    #[test]
+    fn geometric_below_arithmetic() {
+        let acc = accumulate(0, vec![update(1, 0, 100), update(2, 10, 400)]);
+        let end = WindowEnd::Time(20);
+        let geometric = acc.average(Method::Geometric, end).unwrap();
+        assert_eq!(geometric, Decimal::new(200, 0));
+        assert_eq!(
+            acc.average(Method::Arithmetic, end).unwrap(),
+            Decimal::new(250, 0)
+        );
+    }
+    #[test]
+    fn ema_half_life() {
+        // 100 then 200 held for exactly one half life
+        let acc = accumulate(0, vec![update(1, 0, 100), update(2, 10, 200)]);
+        let ema = acc.average(Method::Ema { half_life: 10 }, WindowEnd::Time(20));
+        assert_eq!(ema.unwrap(), Decimal::new(150, 0));
+    }
+    #[test]
+    fn conf_weighted_prefers_tight_conf() {
+        let mut a = update(1, 0, 100);
+        a.conf = 1;
+        let mut b = update(2, 10, 200);
+        b.conf = 3;
+        let acc = accumulate(0, vec![a, b]);
+        let average = acc.average(Method::ConfidenceWeighted, WindowEnd::Time(20));
+        // weights 10/1 and 10/9
+        assert_eq!(average.unwrap(), Decimal::new(110, 0));
+    }
+    #[test]
+    fn parses_methods() {
+        assert_eq!(Method::parse("median", None), Ok(Method::Median));
+        assert!(Method::parse("ema", None).is_err());
+        assert_eq!(
+            Method::parse("ema", Some(60)),
+            Ok(Method::Ema { half_life: 60 })
+        );
+        assert!(Method::parse("vwap", None).is_err());
+    }
+    #[test]
+    fn twac_and_bands() {
+        let mut a = update(1, 0, 100);
+        a.conf = 2;
+        let mut b = update(2, 10, 200);
+        b.conf = 6;
+        let acc = accumulate(0, vec![a, b]);
+        let end = WindowEnd::Time(40);
+        // conf 2 for 10s, 6 for 30s
+        assert_eq!(acc.twac(end).unwrap(), Decimal::new(5, 0));
+        let (lower, upper) = acc.twap_bands(end).unwrap();
+        assert_eq!(lower, Decimal::new(170, 0));
+        assert_eq!(upper, Decimal::new(180, 0));
+    }
+}
@@ -0,0 +1,427 @@
+use crate::aggregate;
+use crate::decimal::Decimal;
+use crate::pyth::{parse_price, PriceAccount, PriceLookup, PythClient, Status};
+use crate::retry::RetryPolicy;
+use crate::twap::{Ohlc, PriceUpdate, TwapAccumulator};
+use crate::window::WindowEnd;
+use serde_json::{json, Value};
+use solana_account_decoder::UiAccount;
+use solana_program::pubkey::Pubkey;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tungstenite::client::AutoStream;
+use tungstenite::{Message, WebSocket};
+
+// the aggregate price over the last interval seconds, moved forward as aggregates arrive
+// aggregates are kept as updates with the default key as publisher
+pub struct RollingTwap {
+    interval: i64,
+    expo: i32,
+    // oldest first
+    aggregates: VecDeque<PriceUpdate>,
+}
+
+// the rolling window as of its end
+pub struct RollingSnapshot {
+    pub start: i64,
+    pub end: i64,
+    pub ohlc: Ohlc,
+    pub twap: Decimal,
+    pub twac: Decimal,
+    pub count: usize,
+}
+
+impl RollingTwap {
+    pub fn new(interval: i64, expo: i32) -> RollingTwap {
+        RollingTwap {
+            interval,
+            expo,
+            aggregates: VecDeque::new(),
+        }
+    }
+    // add an aggregate, false when it is not newer than the latest one
+    pub fn push(&mut self, aggregate: PriceUpdate) -> bool {
+        if let Some(last) = self.aggregates.back() {
+            if aggregate.pub_slot <= last.pub_slot {
+                return false;
+            }
+        }
+        self.aggregates.push_back(aggregate);
+        true
+    }
+    pub fn len(&self) -> usize {
+        self.aggregates.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.aggregates.is_empty()
+    }
+    // slide the window to end at now, dropping the aggregates it left behind
+    // None while no aggregate is left in the window
+    pub fn snapshot(&mut self, now: i64) -> Option<RollingSnapshot> {
+        let start = now - self.interval;
+        while matches!(self.aggregates.front(), Some(a) if a.block_time < start) {
+            self.aggregates.pop_front();
+        }
+        let mut acc = TwapAccumulator::new(self.expo);
+        for a in &self.aggregates {
+            acc.ingest(a.clone());
+        }
+        let end = WindowEnd::Time(now);
+        Some(RollingSnapshot {
+            start,
+            end: now,
+            ohlc: acc.ohlc()?,
+            twap: acc.twap(end)?,
+            twac: acc.twac(end)?,
+            count: acc.count(),
+        })
+    }
+}
+
+// the aggregates pyth computed from the publisher updates, oldest first, each stamped
+// with the time of the transaction that triggered it, to seed a RollingTwap with
+pub fn history(updates: &[PriceUpdate], expo: i32) -> Vec<PriceUpdate> {
+    let mut acc = TwapAccumulator::new(expo);
+    let mut times = HashMap::new();
+    for u in updates {
+        times.entry(u.slot).or_insert(u.block_time);
+        acc.ingest(u.clone());
+    }
+    aggregate::reconstruct(&acc, 1)
+        .into_iter()
+        .map(|a| PriceUpdate {
+            pub_slot: a.slot,
+            slot: a.slot,
+            block_time: times[&a.slot],
+            // reconstructed at the account exponent
+            price: a.price.mantissa as i64,
+            conf: a.conf.mantissa as u64,
+            status: Status::Trading,
+            publisher: Pubkey::default(),
+        })
+        .collect()
+}
+
+// the latest aggregate of the price account, seen at block_time
+pub fn aggregate_update(account: &PriceAccount, block_time: i64) -> PriceUpdate {
+    PriceUpdate {
+        pub_slot: account.agg_pub_slot,
+        slot: account.agg_pub_slot,
+        block_time,
+        price: account.agg_price,
+        conf: account.agg_conf,
+        status: account.agg_status,
+        publisher: Pubkey::default(),
+    }
+}
+
+// the websocket endpoint of an rpc node, solana serves it one port above the http port
+pub fn websocket_url(rpc_url: &str) -> Result<String, &'static str> {
+    let mut url = match reqwest::Url::parse(rpc_url) {
+        Ok(url) => url,
+        Err(_) => return Err("not a valid rpc url"),
+    };
+    let scheme = match url.scheme() {
+        "http" => "ws",
+        "https" => "wss",
+        _ => return Err("rpc url should be http or https"),
+    };
+    if url.set_scheme(scheme).is_err() {
+        return Err("not a valid rpc url");
+    }
+    if let Some(port) = url.port() {
+        if url.set_port(Some(port + 1)).is_err() {
+            return Err("not a valid rpc url");
+        }
+    }
+    Ok(url.to_string())
+}
+
+// new states of the price account while watching it
+pub enum PriceFeed {
+    // read the account every interval
+    Poll {
+        pyth: Arc<PythClient>,
+        key: Pubkey,
+        every: Duration,
+    },
+    // accountSubscribe notifications over a websocket
+    Subscribe(Box<AccountSubscription>),
+}
+
+impl PriceFeed {
+    // blocks until the next state of the account
+    pub fn recv(&mut self) -> Result<PriceAccount, &'static str> {
+        match self {
+            PriceFeed::Poll { pyth, key, every } => {
+                thread::sleep(*every);
+                pyth.get_price_account(key.to_bytes())
+            }
+            PriceFeed::Subscribe(subscription) => subscription.recv(),
+        }
+    }
+}
+
+// solana-client 1.7 has no accountSubscribe, so the subscription is made directly
+// a dropped connection is reopened and the account subscribed to again
+pub struct AccountSubscription {
+    socket: WebSocket<AutoStream>,
+    key: Pubkey,
+    ws_url: String,
+    // how reconnecting backs off
+    retry: RetryPolicy,
+}
+
+impl AccountSubscription {
+    pub fn connect(
+        ws_url: &str,
+        key: &Pubkey,
+        retry: RetryPolicy,
+    ) -> Result<AccountSubscription, &'static str> {
+        Ok(AccountSubscription {
+            socket: subscribe(ws_url, key)?,
+            key: *key,
+            ws_url: ws_url.to_string(),
+            retry,
+        })
+    }
+    // subscribe again with backoff, the error of the last attempt once they run out
+    // the account changes while the connection was down are not seen
+    fn reconnect(&mut self) -> Result<(), &'static str> {
+        let mut attempts = 0;
+        loop {
+            thread::sleep(self.retry.delay(attempts, false));
+            match subscribe(&self.ws_url, &self.key) {
+                Ok(socket) => {
+                    self.socket = socket;
+                    return Ok(());
+                }
+                Err(e) => {
+                    attempts += 1;
+                    if attempts >= self.retry.max_attempts {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+    pub fn recv(&mut self) -> Result<PriceAccount, &'static str> {
+        loop {
+            let mut message = match read(&mut self.socket)? {
+                Some(message) => message,
+                None => {
+                    self.reconnect()?;
+                    continue;
+                }
+            };
+            if message["method"] != "accountNotification" {
+                continue;
+            }
+            let account: UiAccount =
+                match serde_json::from_value(message["params"]["result"]["value"].take()) {
+                    Ok(account) => account,
+                    Err(_) => return Err("unexpected account notification"),
+                };
+            let account = match account.decode::<solana_sdk::account::Account>() {
+                Some(account) => account,
+                None => return Err("unexpected account notification"),
+            };
+            return match parse_price(self.key, account.owner, &account.data)? {
+                PriceLookup::Found(price_account) => Ok(price_account),
+                PriceLookup::Next(_) => Err("subscribed account is not a valid price account"),
+            };
+        }
+    }
+}
+
+// open a websocket and subscribe to the account on it
+fn subscribe(ws_url: &str, key: &Pubkey) -> Result<WebSocket<AutoStream>, &'static str> {
+    let mut socket = match tungstenite::connect(ws_url) {
+        Ok((socket, _)) => socket,
+        Err(_) => return Err("cannot connect to the rpc websocket"),
+    };
+    let request = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "accountSubscribe",
+        "params": [key.to_string(), { "encoding": "base64", "commitment": "confirmed" }],
+    });
+    if socket
+        .write_message(Message::Text(request.to_string()))
+        .is_err()
+    {
+        return Err("cannot subscribe to the price account");
+    }
+    match read(&mut socket)? {
+        Some(reply) if reply["result"].is_u64() => Ok(socket),
+        Some(_) => Err("rpc node refused the price account subscription"),
+        None => Err("rpc websocket closed"),
+    }
+}
+
+// the next json message, None once the connection is gone, pings are answered by tungstenite
+fn read(socket: &mut WebSocket<AutoStream>) -> Result<Option<Value>, &'static str> {
+    loop {
+        match socket.read_message() {
+            Ok(Message::Text(text)) => match serde_json::from_str(&text) {
+                Ok(value) => return Ok(Some(value)),
+                Err(_) => return Err("rpc websocket message is not json"),
+            },
+            Ok(Message::Close(_)) | Err(_) => return Ok(None),
+            Ok(_) => continue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decimal::Decimal;
+    use crate::pyth::Status;
+    use crate::retry::RetryPolicy;
+    use crate::twap::PriceUpdate;
+    use crate::watch::{history, websocket_url, AccountSubscription, RollingTwap};
+    use pyth_client::{AccountType, Price, PriceStatus, PriceType, MAGIC, VERSION_2};
+    use serde_json::{json, Value};
+    use solana_account_decoder::{UiAccount, UiAccountEncoding};
+    use solana_program::pubkey::Pubkey;
+    use solana_sdk::account::Account;
+    use std::mem::{size_of, zeroed};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+    use tungstenite::Message;
+
+    fn update(slot: u64, block_time: i64, price: i64, publisher: u8) -> PriceUpdate {
+        PriceUpdate {
+            pub_slot: slot,
+            slot,
+            block_time,
+            price,
+            conf: 1,
+            status: Status::Trading,
+            publisher: Pubkey::new(&[publisher; 32]),
+        }
+    }
+
+    #[test]
+    fn slides_the_window() {
+        let mut rolling = RollingTwap::new(100, -2);
+        assert!(rolling.push(update(1, 1000, 100, 0)));
+        assert!(rolling.push(update(2, 1050, 300, 0)));
+        assert!(!rolling.push(update(2, 1060, 500, 0)));
+        let snapshot = rolling.snapshot(1100).unwrap();
+        assert_eq!(snapshot.twap, Decimal::from_price(200, -2));
+        assert_eq!(snapshot.count, 2);
+
+        // the first aggregate falls out of the window
+        assert!(rolling.push(update(3, 1100, 500, 0)));
+        let snapshot = rolling.snapshot(1150).unwrap();
+        assert_eq!(snapshot.count, 2);
+        assert_eq!(snapshot.ohlc.open, Decimal::from_price(300, -2));
+        assert_eq!(snapshot.twap, Decimal::from_price(400, -2));
+        assert!(rolling.snapshot(1400).is_none());
+        assert!(rolling.is_empty());
+    }
+    #[test]
+    fn seeds_from_publisher_updates() {
+        // the update landing in slot 3 aggregates both components of slot 2
+        let updates = vec![
+            update(2, 1000, 100, 1),
+            update(2, 1000, 300, 2),
+            update(3, 1004, 500, 1),
+        ];
+        let aggregates = history(&updates, -2);
+        assert_eq!(aggregates.len(), 1);
+        assert_eq!(aggregates[0].slot, 3);
+        assert_eq!(aggregates[0].block_time, 1004);
+        assert_eq!(aggregates[0].price, 200);
+    }
+    #[test]
+    fn derives_websocket_url() {
+        assert_eq!(
+            websocket_url("http://api.devnet.solana.com").unwrap(),
+            "ws://api.devnet.solana.com/"
+        );
+        assert_eq!(
+            websocket_url("http://localhost:8899").unwrap(),
+            "ws://localhost:8900/"
+        );
+        assert!(websocket_url("ftp://localhost").is_err());
+    }
+    #[test]
+    fn resubscribes_after_a_drop() {
+        let key = Pubkey::new(&[3; 32]);
+        let owner = Pubkey::new(&[4; 32]);
+        let mut price: Price = unsafe { zeroed() };
+        price.magic = MAGIC;
+        price.ver = VERSION_2;
+        price.atype = AccountType::Price as u32;
+        price.ptype = PriceType::Price;
+        price.expo = -2;
+        price.agg.price = 12345;
+        price.agg.status = PriceStatus::Trading;
+        let data = unsafe {
+            std::slice::from_raw_parts(&price as *const Price as *const u8, size_of::<Price>())
+        };
+        let account = Account {
+            lamports: 1,
+            data: data.to_vec(),
+            owner,
+            executable: false,
+            rent_epoch: 0,
+        };
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "accountNotification",
+            "params": {
+                "result": {
+                    "context": { "slot": 1 },
+                    "value": UiAccount::encode(&key, &account, UiAccountEncoding::Base64, None, None),
+                },
+                "subscription": 1,
+            },
+        });
+
+        // the node drops the first connection after the subscription, then notifies on the second
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let node = thread::spawn(move || {
+            let mut subscriptions = 0;
+            for (connection, stream) in listener.incoming().take(2).enumerate() {
+                let mut socket = tungstenite::accept(stream.unwrap()).unwrap();
+                let request: Value = match socket.read_message().unwrap() {
+                    Message::Text(text) => serde_json::from_str(&text).unwrap(),
+                    _ => panic!("expected a subscription"),
+                };
+                assert_eq!(request["method"], "accountSubscribe");
+                subscriptions += 1;
+                let reply = json!({ "jsonrpc": "2.0", "result": 1, "id": 1 });
+                socket
+                    .write_message(Message::Text(reply.to_string()))
+                    .unwrap();
+                if connection == 0 {
+                    socket.close(None).unwrap();
+                    let _ = socket.write_pending();
+                    continue;
+                }
+                socket
+                    .write_message(Message::Text(notification.to_string()))
+                    .unwrap();
+                let _ = socket.read_message();
+            }
+            subscriptions
+        });
+
+        let retry = RetryPolicy {
+            base_delay: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        };
+        let mut subscription = AccountSubscription::connect(&url, &key, retry).unwrap();
+        let price_account = subscription.recv().unwrap();
+        assert_eq!(price_account.agg_price, 12345);
+        drop(subscription);
+        assert_eq!(node.join().unwrap(), 2);
+    }
+}
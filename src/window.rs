@@ -0,0 +1,210 @@
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use std::fmt;
+
+// range the TWAP is calculated over, either wall clock time or solana slots
+#[derive(Clone, Copy)]
+pub enum Window {
+    Time {
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    },
+    // inclusive on both ends, the way an on-chain consumer reading at slot `to` sees it
+    Slots {
+        from: u64,
+        to: u64,
+    },
+}
+
+// where the window closes, prices are weighted by seconds or by slots until it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WindowEnd {
+    Time(i64),
+    Slot(u64),
+}
+
+// where a price account signature falls relative to the window
+// signatures are walked newest first so Older means we are done
+#[derive(Debug, PartialEq)]
+pub enum Position {
+    Newer,
+    Inside,
+    Older,
+}
+
+impl Window {
+    // the interval leading up to now
+    pub fn last(interval: Duration) -> Window {
+        let end = Utc::now();
+        Window::Time {
+            start: end - interval,
+            end,
+        }
+    }
+    pub fn between(start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Window, &'static str> {
+        if start >= end {
+            return Err("window start should be before the window end");
+        }
+        Ok(Window::Time { start, end })
+    }
+    pub fn slots(from: u64, to: u64) -> Result<Window, &'static str> {
+        if from > to {
+            return Err("from slot should not be after the to slot");
+        }
+        // slots are weighted as i64 and the window ends one slot after to
+        if to >= i64::MAX as u64 {
+            return Err("to slot is too large");
+        }
+        Ok(Window::Slots { from, to })
+    }
+    pub fn end(&self) -> WindowEnd {
+        match self {
+            Window::Time { end, .. } => WindowEnd::Time(end.timestamp()),
+            Window::Slots { to, .. } => WindowEnd::Slot(to.saturating_add(1)),
+        }
+    }
+    pub fn locate(&self, slot: u64, block_time: i64) -> Position {
+        match self {
+            Window::Time { start, end } => {
+                if block_time > end.timestamp() {
+                    Position::Newer
+                } else if block_time < start.timestamp() {
+                    Position::Older
+                } else {
+                    Position::Inside
+                }
+            }
+            Window::Slots { from, to } => {
+                if slot > *to {
+                    Position::Newer
+                } else if slot < *from {
+                    Position::Older
+                } else {
+                    Position::Inside
+                }
+            }
+        }
+    }
+    // whether a decoded update belongs in the window, slot windows go by publish slot
+    pub fn contains_update(&self, pub_slot: u64) -> bool {
+        match self {
+            Window::Time { .. } => true,
+            Window::Slots { from, to } => pub_slot >= *from && pub_slot <= *to,
+        }
+    }
+    // fraction of the window walked back from its end, for progress reporting
+    pub fn progress(&self, slot: u64, block_time: i64) -> f32 {
+        let (walked, total) = match self {
+            Window::Time { start, end } => (
+                end.timestamp() as i128 - block_time as i128,
+                end.timestamp() as i128 - start.timestamp() as i128,
+            ),
+            Window::Slots { from, to } => {
+                (*to as i128 - slot as i128, *to as i128 - *from as i128 + 1)
+            }
+        };
+        (walked as f32 / total as f32).clamp(0.0, 1.0)
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Window::Time { start, end } => write!(
+                f,
+                "{} - {} ({} minute(s))",
+                start.to_rfc3339(),
+                end.to_rfc3339(),
+                (*end - *start).num_minutes()
+            ),
+            Window::Slots { from, to } => {
+                write!(
+                    f,
+                    "slot {} - {} ({} slot(s))",
+                    from,
+                    to,
+                    *to as u128 - *from as u128 + 1
+                )
+            }
+        }
+    }
+}
+
+// parse a point in time given as RFC3339 (2021-07-01T16:00:00Z) or unix seconds
+pub fn parse_time(s: &str) -> Result<DateTime<Utc>, &'static str> {
+    if let Ok(secs) = s.parse::<i64>() {
+        return match Utc.timestamp_opt(secs, 0).single() {
+            Some(t) => Ok(t),
+            None => Err("unix timestamp out of range"),
+        };
+    }
+    match DateTime::parse_from_rfc3339(s) {
+        Ok(t) => Ok(t.with_timezone(&Utc)),
+        Err(_) => Err("time should be RFC3339 or unix seconds"),
+    }
+}
+
+// a duration like 30s, 5m, 1h or 1d in seconds
+pub fn parse_duration(s: &str) -> Result<i64, &'static str> {
+    let unit = match s.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 60 * 60,
+        Some('d') => 24 * 60 * 60,
+        _ => return Err("duration should end in s, m, h or d"),
+    };
+    match s[..s.len() - 1].parse::<i64>() {
+        Ok(n) if n > 0 => n.checked_mul(unit).ok_or("duration is too large"),
+        _ => Err("duration should be a positive number"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::window::{parse_time, Position, Window, WindowEnd};
+
+    #[test]
+    fn parses_rfc3339_and_unix() {
+        let a = parse_time("2021-07-01T16:00:00Z").unwrap();
+        let b = parse_time("2021-07-01T18:00:00+02:00").unwrap();
+        let c = parse_time("1625155200").unwrap();
+        assert_eq!(a, b);
+        assert_eq!(a, c);
+        assert!(parse_time("yesterday").is_err());
+    }
+    #[test]
+    fn rejects_empty_window() {
+        let t = parse_time("1625155200").unwrap();
+        assert!(Window::between(t, t).is_err());
+        assert!(Window::slots(10, 9).is_err());
+        assert!(Window::slots(0, u64::MAX).is_err());
+    }
+    #[test]
+    fn widest_slot_window() {
+        let w = Window::Slots {
+            from: 0,
+            to: u64::MAX,
+        };
+        assert_eq!(w.end(), WindowEnd::Slot(u64::MAX));
+        assert_eq!(w.progress(u64::MAX, 0), 0.0);
+        assert!(w.to_string().ends_with("(18446744073709551616 slot(s))"));
+    }
+    #[test]
+    fn locates_signatures_by_slot() {
+        let w = Window::slots(100, 200).unwrap();
+        assert_eq!(w.locate(201, 0), Position::Newer);
+        assert_eq!(w.locate(200, 0), Position::Inside);
+        assert_eq!(w.locate(99, 0), Position::Older);
+        assert_eq!(w.end(), WindowEnd::Slot(201));
+        assert!(!w.contains_update(99));
+    }
+    #[test]
+    fn locates_signatures_by_time() {
+        let start = parse_time("1625155200").unwrap();
+        let end = parse_time("1625158800").unwrap();
+        let w = Window::between(start, end).unwrap();
+        assert_eq!(w.locate(0, 1625158801), Position::Newer);
+        assert_eq!(w.locate(0, 1625155200), Position::Inside);
+        assert_eq!(w.locate(0, 1625155199), Position::Older);
+        assert_eq!(w.progress(0, 1625157000), 0.5);
+    }
+}
@@ -0,0 +1,396 @@
+// an in-process solana json-rpc node serving a canned ledger, and pyth accounts to put in it
+#![allow(dead_code)]
+
+use pyth_client::{
+    AccKey, AccountType, Mapping, Price, PriceStatus, PriceType, Product, MAGIC, PROD_ATTR_SIZE,
+    VERSION_2,
+};
+use serde_json::{json, Value};
+use solana_account_decoder::{UiAccount, UiAccountEncoding};
+use solana_client::rpc_response::RpcConfirmedTransactionStatusWithSignature;
+use solana_program::instruction::{AccountMeta, Instruction};
+use solana_program::message::Message;
+use solana_program::pubkey::Pubkey;
+use solana_program::sysvar;
+use solana_sdk::account::Account;
+use solana_sdk::signature::Signature;
+use solana_sdk::transaction::{Transaction, TransactionError};
+use solana_transaction_status::{
+    EncodedConfirmedTransaction, EncodedTransaction, EncodedTransactionWithStatusMeta,
+    UiTransactionEncoding,
+};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::mem::{size_of, zeroed};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+// what the mock node knows about the chain
+pub struct Ledger {
+    accounts: HashMap<Pubkey, Account>,
+    // the signatures of each address, newest first
+    signatures: HashMap<Pubkey, Vec<RpcConfirmedTransactionStatusWithSignature>>,
+    transactions: HashMap<String, EncodedConfirmedTransaction>,
+    landed: u64,
+    // most signatures returned per page, a real node returns 1000
+    pub page_size: usize,
+    // answer this many requests with http 500 before serving any
+    pub failures: usize,
+}
+
+impl Default for Ledger {
+    fn default() -> Ledger {
+        Ledger {
+            accounts: HashMap::new(),
+            signatures: HashMap::new(),
+            transactions: HashMap::new(),
+            landed: 0,
+            page_size: 1000,
+            failures: 0,
+        }
+    }
+}
+
+impl Ledger {
+    pub fn add_account(&mut self, key: Pubkey, owner: Pubkey, data: Vec<u8>) {
+        let account = Account {
+            lamports: 1,
+            data,
+            owner,
+            executable: false,
+            rent_epoch: 0,
+        };
+        self.accounts.insert(key, account);
+    }
+    // land a transaction as the newest one of every account it touches, returns its signature
+    pub fn land(
+        &mut self,
+        payer: &Pubkey,
+        instructions: &[Instruction],
+        slot: u64,
+        block_time: i64,
+        failed: bool,
+    ) -> String {
+        self.landed += 1;
+        let mut sig = [0u8; 64];
+        sig[..8].copy_from_slice(&self.landed.to_le_bytes());
+        let signature = Signature::new(&sig).to_string();
+
+        let message = Message::new(instructions, Some(payer));
+        let mut transaction = Transaction::new_unsigned(message);
+        transaction.signatures = vec![Signature::new(&sig)];
+        for key in &transaction.message.account_keys {
+            let status = RpcConfirmedTransactionStatusWithSignature {
+                signature: signature.clone(),
+                slot,
+                err: if failed {
+                    Some(TransactionError::AccountNotFound)
+                } else {
+                    None
+                },
+                memo: None,
+                block_time: Some(block_time),
+                confirmation_status: None,
+            };
+            self.signatures.entry(*key).or_default().insert(0, status);
+        }
+        let encoded = EncodedConfirmedTransaction {
+            slot,
+            transaction: EncodedTransactionWithStatusMeta {
+                transaction: EncodedTransaction::encode(transaction, UiTransactionEncoding::Base64),
+                meta: None,
+            },
+            block_time: Some(block_time),
+        };
+        self.transactions.insert(signature.clone(), encoded);
+        signature
+    }
+    fn account(&self, key: &str) -> Value {
+        let key = match key.parse::<Pubkey>() {
+            Ok(key) => key,
+            Err(_) => return Value::Null,
+        };
+        match self.accounts.get(&key) {
+            Some(account) => json!(UiAccount::encode(
+                &key,
+                account,
+                UiAccountEncoding::Base64,
+                None,
+                None
+            )),
+            None => Value::Null,
+        }
+    }
+    fn signature_page(&self, key: &str, config: &Value) -> Value {
+        let all = match key
+            .parse::<Pubkey>()
+            .ok()
+            .and_then(|k| self.signatures.get(&k))
+        {
+            Some(all) => all,
+            None => return json!([]),
+        };
+        let position = |field: &str| {
+            config[field]
+                .as_str()
+                .and_then(|s| all.iter().position(|sig| sig.signature == s))
+        };
+        let start = position("before").map_or(0, |i| i + 1);
+        let end = position("until").unwrap_or(all.len()).max(start);
+        let limit = config["limit"]
+            .as_u64()
+            .map_or(self.page_size, |l| l as usize);
+        let page: Vec<_> = all[start..end]
+            .iter()
+            .take(limit.min(self.page_size))
+            .collect();
+        json!(page)
+    }
+    fn handle(&self, method: &str, params: &Value) -> Result<Value, (i64, &'static str)> {
+        let context = json!({ "slot": 1 });
+        match method {
+            "getVersion" => Ok(json!({ "solana-core": "1.7.3", "feature-set": 0 })),
+            "getAccountInfo" => Ok(json!({
+                "context": context,
+                "value": self.account(params[0].as_str().unwrap_or_default()),
+            })),
+            "getMultipleAccounts" => {
+                let keys = params[0].as_array().cloned().unwrap_or_default();
+                let accounts: Vec<Value> = keys
+                    .iter()
+                    .map(|k| self.account(k.as_str().unwrap_or_default()))
+                    .collect();
+                Ok(json!({ "context": context, "value": accounts }))
+            }
+            "getSignaturesForAddress" => {
+                Ok(self.signature_page(params[0].as_str().unwrap_or_default(), &params[1]))
+            }
+            "getTransaction" => {
+                let signature = params[0].as_str().unwrap_or_default();
+                Ok(json!(self.transactions.get(signature)))
+            }
+            _ => Err((-32601, "Method not found")),
+        }
+    }
+}
+
+// the mock node, listening on a free local port until the test process exits
+pub struct MockRpc {
+    pub url: String,
+    ledger: Arc<Mutex<Ledger>>,
+    calls: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockRpc {
+    pub fn start(ledger: Ledger) -> MockRpc {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let ledger = Arc::new(Mutex::new(ledger));
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        {
+            let ledger = Arc::clone(&ledger);
+            let calls = Arc::clone(&calls);
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let ledger = Arc::clone(&ledger);
+                    let calls = Arc::clone(&calls);
+                    thread::spawn(move || serve(stream, &ledger, &calls));
+                }
+            });
+        }
+        MockRpc { url, ledger, calls }
+    }
+    pub fn ledger(&self) -> MutexGuard<'_, Ledger> {
+        self.ledger.lock().unwrap()
+    }
+    // how many times the method was called so far
+    pub fn calls(&self, method: &str) -> usize {
+        self.calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|m| *m == method)
+            .count()
+    }
+    pub fn reset_calls(&self) {
+        self.calls.lock().unwrap().clear();
+    }
+}
+
+// answer the http requests of one connection until the client hangs up
+fn serve(stream: TcpStream, ledger: &Mutex<Ledger>, calls: &Mutex<Vec<String>>) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut length = 0;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match reader.read_line(&mut line) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    length = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+        let mut body = vec![0u8; length];
+        if reader.read_exact(&mut body).is_err() {
+            return;
+        }
+
+        let (status, reply) = {
+            let mut ledger = ledger.lock().unwrap();
+            if ledger.failures > 0 {
+                ledger.failures -= 1;
+                ("500 Internal Server Error", String::new())
+            } else {
+                ("200 OK", respond(&ledger, calls, &body).to_string())
+            }
+        };
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            reply.len(),
+            reply
+        );
+        if writer.write_all(response.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+fn respond(ledger: &Ledger, calls: &Mutex<Vec<String>>, body: &[u8]) -> Value {
+    let request: Value = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(_) => {
+            return json!({
+                "jsonrpc": "2.0",
+                "id": null,
+                "error": { "code": -32700, "message": "Parse error" },
+            })
+        }
+    };
+    let method = request["method"].as_str().unwrap_or_default();
+    calls.lock().unwrap().push(method.to_string());
+    match ledger.handle(method, &request["params"]) {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }),
+        Err((code, message)) => json!({
+            "jsonrpc": "2.0",
+            "id": request["id"],
+            "error": { "code": code, "message": message },
+        }),
+    }
+}
+
+fn bytes<T>(value: &T) -> Vec<u8> {
+    unsafe { std::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }.to_vec()
+}
+
+// a pyth program with one mapping account listing one product and its price account
+pub struct PythFixture {
+    pub program_id: Pubkey,
+    pub mapping_key: Pubkey,
+    pub product_key: Pubkey,
+    pub price_key: Pubkey,
+    pub publisher: Pubkey,
+}
+
+impl PythFixture {
+    // the price account holds twap and an aggregate of agg_price +/- agg_conf at agg_slot
+    pub fn new(
+        ledger: &mut Ledger,
+        symbol: &str,
+        expo: i32,
+        twap: i64,
+        agg: (i64, u64, u64),
+    ) -> PythFixture {
+        let fixture = PythFixture {
+            program_id: Pubkey::new(&[10; 32]),
+            mapping_key: Pubkey::new(&[11; 32]),
+            product_key: Pubkey::new(&[12; 32]),
+            price_key: Pubkey::new(&[13; 32]),
+            publisher: Pubkey::new(&[14; 32]),
+        };
+
+        let mut mapping: Mapping = unsafe { zeroed() };
+        mapping.magic = MAGIC;
+        mapping.ver = VERSION_2;
+        mapping.atype = AccountType::Mapping as u32;
+        mapping.num = 1;
+        mapping.products[0] = AccKey {
+            val: fixture.product_key.to_bytes(),
+        };
+        ledger.add_account(fixture.mapping_key, fixture.program_id, bytes(&mapping));
+
+        let mut attr = [0u8; PROD_ATTR_SIZE];
+        attr[0] = 6;
+        attr[1..7].copy_from_slice(b"symbol");
+        attr[7] = symbol.len() as u8;
+        attr[8..8 + symbol.len()].copy_from_slice(symbol.as_bytes());
+        let product = Product {
+            magic: MAGIC,
+            ver: VERSION_2,
+            atype: AccountType::Product as u32,
+            size: (48 + 8 + symbol.len()) as u32,
+            px_acc: AccKey {
+                val: fixture.price_key.to_bytes(),
+            },
+            attr,
+        };
+        ledger.add_account(fixture.product_key, fixture.program_id, bytes(&product));
+
+        let mut price: Price = unsafe { zeroed() };
+        price.magic = MAGIC;
+        price.ver = VERSION_2;
+        price.atype = AccountType::Price as u32;
+        price.size = size_of::<Price>() as u32;
+        price.ptype = PriceType::Price;
+        price.expo = expo;
+        price.twap = twap;
+        price.valid_slot = agg.2;
+        price.agg.price = agg.0;
+        price.agg.conf = agg.1;
+        price.agg.status = PriceStatus::Trading;
+        price.agg.pub_slot = agg.2;
+        ledger.add_account(fixture.price_key, fixture.program_id, bytes(&price));
+        fixture
+    }
+    // an upd_price instruction from the fixture publisher
+    pub fn upd_price(
+        &self,
+        price_key: &Pubkey,
+        price: i64,
+        conf: u64,
+        pub_slot: u64,
+    ) -> Instruction {
+        let mut data = vec![2, 0, 0, 0, 7, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0];
+        data.extend_from_slice(&price.to_le_bytes());
+        data.extend_from_slice(&conf.to_le_bytes());
+        data.extend_from_slice(&pub_slot.to_le_bytes());
+        Instruction::new_with_bytes(
+            self.program_id,
+            &data,
+            vec![
+                AccountMeta::new(self.publisher, true),
+                AccountMeta::new(*price_key, false),
+                AccountMeta::new_readonly(sysvar::clock::id(), false),
+            ],
+        )
+    }
+    // land an update of the fixture price account published in the slot it landed in
+    pub fn update(&self, ledger: &mut Ledger, slot: u64, block_time: i64, price: i64) -> String {
+        let instruction = self.upd_price(&self.price_key, price, 1, slot);
+        ledger.land(&self.publisher, &[instruction], slot, block_time, false)
+    }
+}
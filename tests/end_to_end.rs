@@ -0,0 +1,171 @@
+mod common;
+
+use common::{Ledger, MockRpc, PythFixture};
+use pyth_twap::pyth::PythClient;
+use pyth_twap::retry::RetryPolicy;
+use pyth_twap::source::{FileSource, PriceUpdateSource, RpcSource};
+use pyth_twap::watch::{self, PriceFeed, RollingTwap};
+use pyth_twap::{compute_twap, compute_twap_from, Decimal, TwapOptions, Window};
+use solana_program::pubkey::Pubkey;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+// BTC/USD updated at slots 10, 15 and 20, a failed update at 25 and one after the window at 40
+// the slot 15 transaction batches an update for another price account
+fn btc_node() -> (MockRpc, PythFixture) {
+    let mut ledger = Ledger::default();
+    // small pages so the signatures are walked over several calls
+    ledger.page_size = 2;
+    let pyth = PythFixture::new(&mut ledger, "BTC/USD", -2, 150, (200, 2, 20));
+    pyth.update(&mut ledger, 10, 1010, 100);
+    let other = pyth.upd_price(&Pubkey::new(&[20; 32]), 5000, 1, 15);
+    let ours = pyth.upd_price(&pyth.price_key, 100, 1, 15);
+    ledger.land(&pyth.publisher, &[other, ours], 15, 1015, false);
+    pyth.update(&mut ledger, 20, 1020, 200);
+    let failed = pyth.upd_price(&pyth.price_key, 900, 1, 25);
+    ledger.land(&pyth.publisher, &[failed], 25, 1025, true);
+    pyth.update(&mut ledger, 40, 1040, 900);
+    (MockRpc::start(ledger), pyth)
+}
+
+fn options(node: &MockRpc, pyth: &PythFixture) -> TwapOptions {
+    TwapOptions {
+        url: node.url.clone(),
+        mapping_key: pyth.mapping_key.to_string(),
+        retry: RetryPolicy {
+            base_delay: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        },
+        ..TwapOptions::default()
+    }
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("pyth-twap-{}-{}", name, std::process::id()))
+}
+
+#[test]
+fn computes_twap_from_symbol() {
+    let (node, pyth) = btc_node();
+    let window = Window::slots(10, 29).unwrap();
+    let report = compute_twap("BTC/USD", window, &options(&node, &pyth)).unwrap();
+
+    assert_eq!(report.product_key, pyth.product_key);
+    assert_eq!(report.price_key, pyth.price_key);
+    assert_eq!(report.num_updates, 3);
+    assert_eq!(report.ohlc.open, Decimal::from_price(100, -2));
+    assert_eq!(report.ohlc.close, Decimal::from_price(200, -2));
+    assert_eq!(report.twap, Decimal::from_price(150, -2));
+    assert!(report.onchain.unwrap().deviation.is_zero());
+    // the failed transaction and the one after the window are never fetched
+    assert_eq!(node.calls("getTransaction"), 3);
+    assert!(node.calls("getSignaturesForAddress") >= 3);
+}
+
+#[test]
+fn reports_unknown_symbol() {
+    let (node, pyth) = btc_node();
+    let window = Window::slots(10, 29).unwrap();
+    assert!(compute_twap("DOGE/USD", window, &options(&node, &pyth)).is_err());
+}
+
+#[test]
+fn retries_failing_node() {
+    let (node, pyth) = btc_node();
+    node.ledger().failures = 2;
+    let window = Window::slots(10, 29).unwrap();
+    let report = compute_twap("BTC/USD", window, &options(&node, &pyth)).unwrap();
+    assert_eq!(report.num_updates, 3);
+}
+
+#[test]
+fn fetches_only_uncached_transactions() {
+    let (node, pyth) = btc_node();
+    let dir = temp_path("cache");
+    let options = TwapOptions {
+        cache_dir: Some(dir.clone()),
+        ..options(&node, &pyth)
+    };
+    let window = Window::slots(10, 29).unwrap();
+    let first = compute_twap("BTC/USD", window, &options).unwrap();
+    assert_eq!(node.calls("getTransaction"), 3);
+
+    node.reset_calls();
+    let again = compute_twap("BTC/USD", window, &options).unwrap();
+    assert_eq!(again.twap, first.twap);
+    assert_eq!(node.calls("getTransaction"), 0);
+
+    // slot 40 was walked past but never fetched, slot 50 is new
+    pyth.update(&mut node.ledger(), 50, 1050, 300);
+    node.reset_calls();
+    let wider = compute_twap("BTC/USD", Window::slots(10, 59).unwrap(), &options).unwrap();
+    assert_eq!(wider.num_updates, 5);
+    assert_eq!(node.calls("getTransaction"), 2);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn replays_exported_updates() {
+    let (node, pyth) = btc_node();
+    let path = temp_path("export.json");
+    let options = TwapOptions {
+        export: Some(path.clone()),
+        ..options(&node, &pyth)
+    };
+    let window = Window::slots(10, 29).unwrap();
+    let fetched = compute_twap("BTC/USD", window, &options).unwrap();
+
+    node.reset_calls();
+    let mut source = FileSource::open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let product_key = source.export.product_key().unwrap();
+    let price_account = source.export.price_account().unwrap();
+    let replayed = compute_twap_from(
+        &mut source,
+        "BTC/USD",
+        &product_key,
+        &price_account,
+        Window::slots(15, 29).unwrap(),
+        &TwapOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(replayed.product_key, fetched.product_key);
+    assert_eq!(replayed.num_updates, 2);
+    assert_eq!(replayed.ohlc.open, Decimal::from_price(100, -2));
+    assert_eq!(node.calls("getAccountInfo"), 0);
+}
+
+#[test]
+fn seeds_and_polls_rolling_twap() {
+    let (node, pyth) = btc_node();
+    let client = Arc::new(PythClient::new(&node.url).unwrap());
+    let price_account = client.get_price_account(pyth.price_key.to_bytes()).unwrap();
+    let mut source = RpcSource::new(Arc::clone(&client));
+    let updates = source
+        .updates(&price_account, &Window::slots(10, 45).unwrap())
+        .unwrap();
+
+    // aggregated by the first update landing in slots 15, 20 and 40
+    let history = watch::history(&updates, price_account.expo);
+    assert_eq!(history.len(), 3);
+    let mut rolling = RollingTwap::new(3600, price_account.expo);
+    for aggregate in history {
+        assert!(rolling.push(aggregate));
+    }
+    let snapshot = rolling.snapshot(1060).unwrap();
+    assert_eq!(snapshot.ohlc.open, Decimal::from_price(100, -2));
+    assert_eq!(snapshot.ohlc.close, Decimal::from_price(200, -2));
+
+    let mut feed = PriceFeed::Poll {
+        pyth: client,
+        key: pyth.price_key,
+        every: Duration::from_millis(1),
+    };
+    let account = feed.recv().unwrap();
+    assert_eq!(account.agg_price, 200);
+    // the on-chain aggregate at slot 20 is older than the seeded history
+    assert!(!rolling.push(watch::aggregate_update(&account, 1060)));
+}
@@ -0,0 +1,85 @@
+mod common;
+
+use common::{Ledger, MockRpc, PythFixture};
+use pyth_twap::retry::RetryPolicy;
+use pyth_twap::{AsyncPythClient, Window};
+use std::sync::Arc;
+use std::time::Duration;
+
+fn retry(max_attempts: u32) -> RetryPolicy {
+    RetryPolicy {
+        max_attempts,
+        base_delay: Duration::from_millis(1),
+        ..RetryPolicy::default()
+    }
+}
+
+#[tokio::test]
+async fn streams_updates_from_symbol() {
+    let mut ledger = Ledger::default();
+    ledger.page_size = 2;
+    let pyth = PythFixture::new(&mut ledger, "ETH/USD", -3, 2000, (2000, 1, 30));
+    for (slot, price) in [(10, 1000), (20, 2000), (30, 3000), (40, 4000)] {
+        pyth.update(&mut ledger, slot, 1000 + slot as i64, price);
+    }
+    let node = MockRpc::start(ledger);
+
+    let client = Arc::new(AsyncPythClient::new(&node.url).unwrap());
+    let product = client
+        .get_product_account(&pyth.mapping_key.to_string(), "ETH/USD")
+        .await
+        .unwrap();
+    assert_eq!(product.key, pyth.product_key);
+    let price = client
+        .get_price_account(product.price_accounts)
+        .await
+        .unwrap();
+    assert_eq!(price.key, pyth.price_key);
+    assert_eq!(price.expo, -3);
+
+    let mut updates = client.stream_updates(&price, Window::slots(15, 35).unwrap(), 4);
+    let mut prices = Vec::new();
+    while let Some(update) = updates.recv().await {
+        prices.push(update.unwrap().price);
+    }
+    // newest first
+    assert_eq!(prices, vec![3000, 2000]);
+}
+
+#[tokio::test]
+async fn retries_failing_node() {
+    let mut ledger = Ledger::default();
+    let pyth = PythFixture::new(&mut ledger, "ETH/USD", -3, 2000, (2000, 1, 30));
+    let node = MockRpc::start(ledger);
+    node.ledger().failures = 2;
+
+    let client = AsyncPythClient::with_retry(&node.url, retry(5)).unwrap();
+    let price = client
+        .get_price_account(pyth.price_key.to_bytes())
+        .await
+        .unwrap();
+    assert_eq!(price.key, pyth.price_key);
+}
+
+#[tokio::test]
+async fn ends_stream_with_error() {
+    let mut ledger = Ledger::default();
+    let pyth = PythFixture::new(&mut ledger, "ETH/USD", -3, 2000, (2000, 1, 30));
+    pyth.update(&mut ledger, 10, 1010, 1000);
+    let node = MockRpc::start(ledger);
+
+    let client = Arc::new(AsyncPythClient::with_retry(&node.url, retry(2)).unwrap());
+    let price = client
+        .get_price_account(pyth.price_key.to_bytes())
+        .await
+        .unwrap();
+    // more failures than attempts
+    node.ledger().failures = 10;
+    let mut updates = client.stream_updates(&price, Window::slots(1, 20).unwrap(), 4);
+    let mut results = Vec::new();
+    while let Some(update) = updates.recv().await {
+        results.push(update);
+    }
+    assert_eq!(results.len(), 1);
+    assert!(results[0].is_err());
+}